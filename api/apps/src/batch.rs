@@ -0,0 +1,223 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use shared::{
+    parse_json, validation::Validator, ApiError, GenerationMessage, GenerationRequest,
+    GenerationSource, GenerationUsage,
+};
+use spin_sdk::http::{Params, Request, Response};
+
+use crate::{
+    completions::{self, validate_messages_only},
+    data::Db,
+    model::App,
+};
+
+const MAX_BATCH_LINES: usize = 100;
+
+/// The outcome of one input line, exactly one of `output` or `error` is set.
+#[derive(Debug, Serialize)]
+pub struct BatchResult {
+    /// 1-based line number in the request body.
+    pub line: usize,
+    /// Copied from the `id` field of the input line so results can be joined back to a dataset.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub id: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<GenerationUsage>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dropped_messages: Vec<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<GenerationSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<BatchError>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct BatchError {
+    pub code: &'static str,
+    pub message: String,
+}
+
+/// A line is either a `GenerationRequest` object or a user prompt, given as a JSON string or
+/// as plain text. Returns the optional `id` along with the messages. The app decides the model
+/// and params, so a request that sets them fails its line rather than running with the app's.
+fn parse_line(line: &str) -> Result<(Option<serde_json::Value>, Vec<GenerationMessage>)> {
+    let trimmed = line.trim();
+    if trimmed.starts_with('{') {
+        let value: serde_json::Value = parse_json(trimmed.as_bytes())?;
+        let id = value.get("id").cloned();
+        let request: GenerationRequest =
+            serde_json::from_value(value).map_err(|e| ApiError::InvalidJson(e.to_string()))?;
+        validate_messages_only(&request)?;
+        let mut v = Validator::new();
+        for (field, set) in [
+            ("model", request.model.is_some()),
+            ("params", request.params.is_some()),
+        ] {
+            v.check(
+                field,
+                !set,
+                "is not supported in a batch, the model and params come from the app",
+            );
+        }
+        v.finish()?;
+        return Ok((id, request.messages));
+    }
+
+    let prompt = match serde_json::from_str::<String>(trimmed) {
+        Ok(prompt) => prompt,
+        Err(_) => trimmed.to_string(),
+    };
+    Ok((
+        None,
+        vec![GenerationMessage {
+            role: "user".to_string(),
+            content: prompt,
+        }],
+    ))
+}
+
+/// Runs one line and stores its completion, errors are reported on the result.
+pub fn run_line(db: &Db, app: &App, line: usize, text: &str) -> BatchResult {
+    let mut result = BatchResult {
+        line,
+        id: None,
+        output: None,
+        usage: None,
+        dropped_messages: Vec::new(),
+        sources: Vec::new(),
+        error: None,
+    };
+
+    let completion = parse_line(text).and_then(|(id, messages)| {
+        result.id = id;
+        let completion = completions::complete(db, app, messages)?;
+        db.insert_completion(&completion)?;
+        Ok(completion)
+    });
+
+    match completion {
+        Ok(completion) => {
+            result.output = Some(completion.output);
+            result.usage = Some(completion.usage);
+            result.dropped_messages = completion.dropped_messages;
+            result.sources = completion.sources;
+        }
+        Err(e) => {
+            let api_error = ApiError::from(&e);
+            result.error = Some(BatchError {
+                code: api_error.code(),
+                message: api_error.to_string(),
+            });
+        }
+    }
+    result
+}
+
+/// The non-empty lines of a JSONL body with their 1-based line numbers.
+pub fn input_lines(body: &str) -> Result<Vec<(usize, &str)>, ApiError> {
+    let lines: Vec<(usize, &str)> = body
+        .lines()
+        .enumerate()
+        .filter(|(_, l)| !l.trim().is_empty())
+        .map(|(i, l)| (i + 1, l))
+        .collect();
+    if lines.is_empty() {
+        return Err(ApiError::BadRequest(
+            "Batch body must contain at least one line".to_string(),
+        ));
+    }
+    Ok(lines)
+}
+
+/// Runs every non-empty line of a JSONL body through the app and responds with one JSON result
+/// per line, in input order. A failing line is reported in its result and the batch carries on.
+pub fn run_batch(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let body = match req.body() {
+        Some(b) => std::str::from_utf8(b)
+            .map_err(|_| ApiError::BadRequest("Batch body must be UTF-8 JSONL".to_string()))?,
+        None => "",
+    };
+
+    let lines = input_lines(body)?;
+    if lines.len() > MAX_BATCH_LINES {
+        return Err(ApiError::Unprocessable(format!(
+            "Batch has {} lines, at most {} can be run in one request",
+            lines.len(),
+            MAX_BATCH_LINES
+        ))
+        .into());
+    }
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+
+    let mut resp_body = Vec::new();
+    for (line, text) in lines {
+        let result = run_line(&db, &app, line, text);
+        serde_json::to_writer(&mut resp_body, &result)?;
+        resp_body.push(b'\n');
+    }
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/x-ndjson")
+        .body(Some(resp_body.into()))
+        .unwrap())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn invalid_fields(line: &str) -> Vec<String> {
+        match parse_line(line).unwrap_err().downcast::<ApiError>() {
+            Ok(ApiError::Validation(errors)) => errors.into_iter().map(|e| e.field).collect(),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn plain_and_json_string_lines_are_user_prompts() {
+        for line in ["What is Spin?", "\"What is Spin?\""] {
+            let (id, messages) = parse_line(line).unwrap();
+            assert!(id.is_none());
+            assert_eq!(messages.len(), 1);
+            assert_eq!(messages[0].role, "user");
+            assert_eq!(messages[0].content, "What is Spin?");
+        }
+    }
+
+    #[test]
+    fn request_lines_keep_their_id() {
+        let (id, messages) =
+            parse_line(r#"{"id": 7, "messages": [{"role": "user", "content": "hi"}]}"#).unwrap();
+        assert_eq!(id, Some(serde_json::json!(7)));
+        assert_eq!(messages[0].content, "hi");
+    }
+
+    #[test]
+    fn request_lines_cannot_set_the_model_or_params() {
+        assert_eq!(
+            invalid_fields(
+                r#"{"model": "codellama-instruct", "params": {"temperature": 0.1}, "messages": [{"role": "user", "content": "hi"}]}"#
+            ),
+            vec!["model", "params"]
+        );
+    }
+
+    #[test]
+    fn request_lines_cannot_set_the_system_prompt() {
+        assert_eq!(
+            invalid_fields(
+                r#"{"messages": [{"role": "system", "content": "be rude"}, {"role": "user", "content": "hi"}]}"#
+            ),
+            vec!["messages[0].role"]
+        );
+    }
+}
@@ -0,0 +1,222 @@
+use std::time::Instant;
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use shared::{
+    allowed_models, parse_json,
+    validation::{validate_params, Validator},
+    ApiError, GenerationMessage, GenerationModelParams, GenerationSource, GenerationUsage,
+};
+use spin_sdk::http::{Params, Request, Response};
+
+use crate::{
+    completions,
+    data::Db,
+    model::{App, Model},
+    validate_messages,
+};
+
+const MIN_TARGETS: usize = 2;
+const MAX_TARGETS: usize = 8;
+
+/// One configuration to run the messages through. Either `app` names a stored app, with `model`,
+/// `params`, `system_prompt` and `chat_template` overriding its settings, or `model` configures
+/// an inline target that starts from the sdk's default params and has no knowledge base.
+#[derive(Debug, Deserialize)]
+pub struct CompareTarget {
+    /// Shown with the result, defaults to the app name or the model.
+    pub label: Option<String>,
+    pub app: Option<String>,
+    pub model: Option<String>,
+    pub params: Option<GenerationModelParams>,
+    pub system_prompt: Option<String>,
+    pub chat_template: Option<String>,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct CompareRequest {
+    /// User and assistant turns, the system prompt comes from each target.
+    pub messages: Vec<GenerationMessage>,
+    pub targets: Vec<CompareTarget>,
+}
+
+/// The outcome for one target, exactly one of `output` or `error` is set.
+#[derive(Debug, Serialize)]
+pub struct CompareResult {
+    pub label: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub app_name: Option<String>,
+    pub model: String,
+    /// The params the target actually ran with, after overrides and defaults.
+    pub params: GenerationModelParams,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<GenerationUsage>,
+    /// Wall time for the target including knowledge retrieval.
+    pub latency_ms: u64,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub dropped_messages: Vec<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<GenerationSource>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Comparison {
+    pub results: Vec<CompareResult>,
+}
+
+/// A target resolved to the app configuration it runs with.
+struct ResolvedTarget {
+    label: String,
+    /// Only stored apps retrieve from a knowledge base.
+    stored: bool,
+    app: App,
+}
+
+/// Looks up and applies the overrides of every target, all problems are reported together
+/// before anything is run.
+fn resolve_targets(db: &Db, targets: &[CompareTarget], v: &mut Validator) -> Vec<ResolvedTarget> {
+    let available_models = allowed_models();
+    let mut resolved = Vec::new();
+
+    for (i, target) in targets.iter().enumerate() {
+        let field = |f: &str| format!("targets[{}].{}", i, f);
+
+        let base = match (&target.app, &target.model) {
+            (Some(name), _) => match db.get(name) {
+                Ok(app) => Some(app),
+                Err(e) => {
+                    let message = match e.downcast_ref::<ApiError>() {
+                        Some(ApiError::NotFound(_)) => "app not found".to_string(),
+                        _ => ApiError::from(&e).to_string(),
+                    };
+                    v.check(&field("app"), false, &message);
+                    None
+                }
+            },
+            (None, Some(model)) => Some(App {
+                model: Model::with_defaults(model),
+                ..Default::default()
+            }),
+            (None, None) => {
+                v.check(&field("app"), false, "either app or model is required");
+                None
+            }
+        };
+        let Some(mut app) = base else {
+            continue;
+        };
+
+        if let Some(model) = &target.model {
+            app.model.name = model.clone();
+        }
+        if let Some(params) = &target.params {
+            app.model = app.model.with_params(params);
+        }
+        if let Some(system_prompt) = &target.system_prompt {
+            app.system_prompt = system_prompt.clone();
+        }
+        if let Some(chat_template) = &target.chat_template {
+            app.chat_template = Some(chat_template.clone());
+        }
+
+        v.check(
+            &field("model"),
+            available_models.contains(&app.model.name),
+            &format!("must be one of: {}", available_models.join(", ")),
+        );
+        validate_params(v, &app.model.name, &(&app.model).into(), |f| {
+            field(&format!("params.{}", f))
+        });
+
+        resolved.push(ResolvedTarget {
+            label: target
+                .label
+                .clone()
+                .or_else(|| target.app.clone())
+                .unwrap_or_else(|| app.model.name.clone()),
+            stored: target.app.is_some(),
+            app,
+        });
+    }
+    resolved
+}
+
+fn run_target(db: &Db, target: ResolvedTarget, messages: &[GenerationMessage]) -> CompareResult {
+    let started = Instant::now();
+    let completion = if target.stored {
+        completions::complete(db, &target.app, messages.to_vec())
+    } else {
+        completions::infer(&target.app, messages.to_vec(), Vec::new())
+    };
+    let latency_ms = started.elapsed().as_millis() as u64;
+
+    let mut result = CompareResult {
+        label: target.label,
+        app_name: target.stored.then(|| target.app.name.clone()),
+        model: target.app.model.name.clone(),
+        params: (&target.app.model).into(),
+        output: None,
+        usage: None,
+        latency_ms,
+        dropped_messages: Vec::new(),
+        sources: Vec::new(),
+        error: None,
+    };
+    match completion {
+        Ok(completion) => {
+            result.output = Some(completion.output);
+            result.usage = Some(completion.usage);
+            result.dropped_messages = completion.dropped_messages;
+            result.sources = completion.sources;
+        }
+        Err(e) => result.error = Some(ApiError::from(&e).to_string()),
+    }
+    result
+}
+
+/// Runs the same messages through each target one after another and returns the results in
+/// target order. Nothing is stored, a failing target is reported in its result.
+pub fn compare(req: Request, _params: Params) -> Result<Response> {
+    let req_body = match req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let compare_req = parse_json::<CompareRequest>(&req_body)?;
+
+    let mut v = Validator::new();
+    v.check(
+        "messages",
+        !compare_req.messages.is_empty(),
+        "must contain at least one message",
+    );
+    validate_messages(&mut v, &compare_req.messages);
+    v.check(
+        "targets",
+        (MIN_TARGETS..=MAX_TARGETS).contains(&compare_req.targets.len()),
+        &format!(
+            "must contain between {} and {} targets",
+            MIN_TARGETS, MAX_TARGETS
+        ),
+    );
+
+    let db = Db::new()?;
+    let targets = resolve_targets(&db, &compare_req.targets, &mut v);
+    v.finish()?;
+
+    let comparison = Comparison {
+        results: targets
+            .into_iter()
+            .map(|t| run_target(&db, t, &compare_req.messages))
+            .collect(),
+    };
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(serde_json::to_vec(&comparison)?.into()))
+        .unwrap())
+}
@@ -0,0 +1,316 @@
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use shared::{
+    validation::Validator, ApiError, GenerationMessage, GenerationRequest, GenerationResponse,
+    GenerationSource, GenerationUsage, InferSdkBuilder, PromptBuilderRegistry,
+};
+use spin_sdk::{
+    http::{Params, Request, Response},
+    sqlite::ValueParam,
+};
+
+use crate::{api::ListQuery, data::Db, knowledge, model::App, validate_messages};
+
+/// A single inference run against an app, as recorded in the completions table.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Completion {
+    #[serde(default)]
+    pub id: i64,
+    #[serde(default)]
+    pub created_at: String,
+    pub app_name: String,
+    pub app_snapshot: App,
+    pub messages: Vec<GenerationMessage>,
+    pub prompt: String,
+    pub output: String,
+    pub usage: GenerationUsage,
+    pub duration_ms: u64,
+    /// Indexes into `messages` that were dropped to fit the model's context window.
+    #[serde(default)]
+    pub dropped_messages: Vec<usize>,
+    /// Knowledge base chunks added to the system prompt.
+    #[serde(default)]
+    pub sources: Vec<GenerationSource>,
+}
+
+/// Configures the sdk builder from the app, the system prompt is prepended so `messages` should
+/// only contain the user and assistant turns.
+fn sdk_builder(
+    app: &App,
+    messages: &[GenerationMessage],
+    sources: &[GenerationSource],
+) -> Result<InferSdkBuilder> {
+    let mut all_messages = vec![GenerationMessage {
+        role: "system".to_string(),
+        content: knowledge::augment_system_prompt(&app.system_prompt, sources),
+    }];
+    all_messages.extend(messages.iter().cloned());
+
+    Ok(InferSdkBuilder::new()
+        .with_model(Some(app.model.name.clone()))
+        .with_messages(all_messages)
+        .with_params(Some((&app.model).into()))
+        .with_chat_template(app.chat_template.clone())
+        .with_prompt_builders(PromptBuilderRegistry::from_config()?))
+}
+
+/// Runs the messages through the app's configuration, adding any relevant chunks from the app's
+/// knowledge base to the system prompt.
+pub fn complete(db: &Db, app: &App, messages: Vec<GenerationMessage>) -> Result<Completion> {
+    let sources = knowledge::retrieve(db, &app.name, &messages)?;
+    infer(app, messages, sources)
+}
+
+/// Runs the messages through the app's configuration with the given chunks in the system
+/// prompt, for configurations that aren't backed by a stored app and so have no knowledge base.
+pub fn infer(
+    app: &App,
+    messages: Vec<GenerationMessage>,
+    sources: Vec<GenerationSource>,
+) -> Result<Completion> {
+    let sdk_bldr = sdk_builder(app, &messages, &sources)?;
+
+    let model = sdk_bldr.build_model()?;
+    let truncated = sdk_bldr.build_truncated_prompt()?;
+    let params = sdk_bldr.build_params();
+
+    let started = Instant::now();
+    let infer_result = spin_sdk::llm::infer_with_options(model, &truncated.prompt, params)?;
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    // the system prompt was prepended so shift the indexes back onto the caller's messages
+    let dropped_messages = truncated.dropped_messages.iter().map(|i| i - 1).collect();
+
+    Ok(Completion {
+        id: 0,
+        created_at: Default::default(),
+        app_name: app.name.clone(),
+        app_snapshot: app.clone(),
+        messages,
+        prompt: truncated.prompt,
+        output: infer_result.text,
+        usage: GenerationUsage {
+            prompt_token_count: infer_result.usage.prompt_token_count,
+            generated_token_count: infer_result.usage.generated_token_count,
+        },
+        duration_ms,
+        dropped_messages,
+        sources,
+    })
+}
+
+// apps always format their own prompt so raw prompts and system messages are rejected rather
+// than ignored
+pub fn validate_messages_only(req: &GenerationRequest) -> Result<(), ApiError> {
+    let mut v = Validator::new();
+    v.check(
+        "prompt",
+        req.prompt.is_none(),
+        "is not supported for apps, use /api/completions for raw prompts",
+    );
+    v.check(
+        "messages",
+        !req.messages.is_empty(),
+        "must contain at least one message",
+    );
+    validate_messages(&mut v, &req.messages);
+    v.finish()
+}
+
+pub fn create_completion(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+
+    // only the messages are used, the model and params come from the app
+    let api_req = GenerationRequest::try_from(req)?;
+    validate_messages_only(&api_req)?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+
+    let completion = complete(&db, &app, api_req.messages)?;
+    db.insert_completion(&completion)?;
+
+    let response = GenerationResponse {
+        messages: vec![GenerationMessage {
+            role: "assistant".to_string(),
+            content: completion.output,
+        }],
+        usage: completion.usage,
+        dropped_messages: completion.dropped_messages,
+        sources: completion.sources,
+    };
+    let body = serde_json::to_vec(&response)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(body.into()))
+        .unwrap())
+}
+
+/// Builds the prompt the app would send for the messages without running inference.
+pub fn preview_completion(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let api_req = GenerationRequest::try_from(req)?;
+    validate_messages_only(&api_req)?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+
+    let sources = knowledge::retrieve(&db, &app.name, &api_req.messages)?;
+    let mut preview = sdk_builder(&app, &api_req.messages, &sources)?.build_preview()?;
+    // the system prompt was prepended so shift the indexes back onto the caller's messages
+    preview.dropped_messages = preview.dropped_messages.iter().map(|i| i - 1).collect();
+
+    let body = serde_json::to_vec(&preview)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(body.into()))
+        .unwrap())
+}
+
+pub fn list_completions(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let list_query: ListQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+
+    let offset = list_query.offset.unwrap_or(0).max(0);
+    let limit = list_query.limit.unwrap_or(10).clamp(1, 100);
+
+    let db = Db::new()?;
+    // 404 for an unknown app instead of an empty list
+    db.get(name)?;
+    let completions = db.list_completions(name, offset, limit)?;
+
+    let body = serde_json::to_vec(&completions)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(body.into()))
+        .unwrap())
+}
+
+impl Db {
+    pub fn insert_completion(&self, completion: &Completion) -> Result<i64> {
+        let app_snapshot = serde_json::to_string(&completion.app_snapshot)?;
+        let messages = serde_json::to_string(&completion.messages)?;
+        let dropped_messages = serde_json::to_string(&completion.dropped_messages)?;
+        let sources = serde_json::to_string(&completion.sources)?;
+
+        let result = self.conn.execute(
+            "INSERT INTO completions (
+          app_name,
+          app_snapshot,
+          messages,
+          prompt,
+          output,
+          prompt_token_count,
+          generated_token_count,
+          duration_ms,
+          dropped_messages,
+          sources
+        )
+      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)
+      RETURNING id",
+            &[
+                ValueParam::Text(&completion.app_name),
+                ValueParam::Text(&app_snapshot),
+                ValueParam::Text(&messages),
+                ValueParam::Text(&completion.prompt),
+                ValueParam::Text(&completion.output),
+                ValueParam::Integer(completion.usage.prompt_token_count as i64),
+                ValueParam::Integer(completion.usage.generated_token_count as i64),
+                ValueParam::Integer(completion.duration_ms as i64),
+                ValueParam::Text(&dropped_messages),
+                ValueParam::Text(&sources),
+            ],
+        )?;
+
+        result
+            .rows
+            .first()
+            .and_then(|r| r.get::<i64>(0))
+            .context("insert did not return the completion id")
+    }
+
+    pub fn list_completions(
+        &self,
+        app_name: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<Completion>> {
+        let result = self.conn.execute(
+            "SELECT * FROM completions WHERE app_name = ? ORDER BY id DESC LIMIT ? OFFSET ?",
+            &[
+                ValueParam::Text(app_name),
+                ValueParam::Integer(limit),
+                ValueParam::Integer(offset),
+            ],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        result
+            .rows
+            .iter()
+            .map(|r| {
+                let app_snapshot = r.get::<&str>(col_map["app_snapshot"]).unwrap_or_default();
+                let messages = r.get::<&str>(col_map["messages"]).unwrap_or_default();
+                let dropped_messages = r.get::<&str>(col_map["dropped_messages"]).unwrap_or("[]");
+                let sources = r.get::<&str>(col_map["sources"]).unwrap_or("[]");
+
+                Ok(Completion {
+                    id: r.get::<i64>(col_map["id"]).unwrap_or_default(),
+                    created_at: r
+                        .get::<&str>(col_map["created_at"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    app_name: r
+                        .get::<&str>(col_map["app_name"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    app_snapshot: serde_json::from_str(app_snapshot)?,
+                    messages: serde_json::from_str(messages)?,
+                    prompt: r
+                        .get::<&str>(col_map["prompt"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    output: r
+                        .get::<&str>(col_map["output"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    usage: GenerationUsage {
+                        prompt_token_count: r
+                            .get::<u32>(col_map["prompt_token_count"])
+                            .unwrap_or_default(),
+                        generated_token_count: r
+                            .get::<u32>(col_map["generated_token_count"])
+                            .unwrap_or_default(),
+                    },
+                    duration_ms: r.get::<i64>(col_map["duration_ms"]).unwrap_or_default() as u64,
+                    dropped_messages: serde_json::from_str(dropped_messages)?,
+                    sources: serde_json::from_str(sources)?,
+                })
+            })
+            .collect()
+    }
+
+    pub fn delete_completions(&self, app_name: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM completions WHERE app_name = ?",
+            &[ValueParam::Text(app_name)],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,337 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use shared::{
+    parse_json, validation::Validator, ApiError, GenerationMessage, GenerationSource,
+    GenerationUsage,
+};
+use spin_sdk::{
+    http::{Params, Request, Response},
+    sqlite::ValueParam,
+};
+
+use crate::{api::ListQuery, completions, data::Db, json_response, route_params};
+
+/// A multi-turn chat with an app, the messages are only populated when fetching a single
+/// conversation.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Conversation {
+    #[serde(default)]
+    pub id: i64,
+    #[serde(default)]
+    pub created_at: String,
+    #[serde(default)]
+    pub updated_at: String,
+    #[serde(default)]
+    pub app_name: String,
+    #[serde(default)]
+    pub title: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub messages: Vec<ConversationMessage>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ConversationMessage {
+    pub id: i64,
+    pub created_at: String,
+    pub role: String,
+    pub content: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewConversation {
+    #[serde(default)]
+    title: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewMessage {
+    content: String,
+}
+
+#[derive(Debug, Serialize)]
+struct ConversationReply {
+    conversation_id: i64,
+    message: GenerationMessage,
+    usage: GenerationUsage,
+    /// Indexes into the conversation's messages, including the new one, that were left out to
+    /// fit the model's context window.
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    dropped_messages: Vec<usize>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    sources: Vec<GenerationSource>,
+}
+
+pub fn create_conversation(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let new_conversation = match req.body() {
+        Some(b) if !b.is_empty() => parse_json::<NewConversation>(b)?,
+        _ => NewConversation {
+            title: Default::default(),
+        },
+    };
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let id = db.insert_conversation(&app.name, &new_conversation.title)?;
+    let conversation = db.get_conversation(&app.name, id)?;
+
+    let resp_body = serde_json::to_vec(&conversation)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::CREATED)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(
+            http::header::LOCATION,
+            format!("/api/apps/{}/conversations/{}", app.name, id),
+        )
+        .body(Some(resp_body.into()))
+        .unwrap())
+}
+
+pub fn list_conversations(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let list_query: ListQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+
+    let offset = list_query.offset.unwrap_or(0).max(0);
+    let limit = list_query.limit.unwrap_or(10).clamp(1, 100);
+
+    let db = Db::new()?;
+    let conversations = db.list_conversations(name, offset, limit)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&conversations)?,
+    ))
+}
+
+pub fn get_conversation(_req: Request, params: Params) -> Result<Response> {
+    let (name, id) = route_params(&params, "id", "Conversation id")?;
+
+    let db = Db::new()?;
+    let conversation = db.get_conversation(name, id)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&conversation)?,
+    ))
+}
+
+pub fn create_message(req: Request, params: Params) -> Result<Response> {
+    let (name, id) = route_params(&params, "id", "Conversation id")?;
+    let req_body = match req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let new_message = parse_json::<NewMessage>(&req_body)?;
+    let mut v = Validator::new();
+    v.check(
+        "content",
+        !new_message.content.trim().is_empty(),
+        "must not be empty",
+    );
+    v.finish()?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let conversation = db.get_conversation(&app.name, id)?;
+
+    // rebuild the transcript from the stored history, the system prompt is added by the app
+    let user_message = GenerationMessage {
+        role: "user".to_string(),
+        content: new_message.content,
+    };
+    let mut messages: Vec<GenerationMessage> = conversation
+        .messages
+        .iter()
+        .map(|m| GenerationMessage {
+            role: m.role.clone(),
+            content: m.content.clone(),
+        })
+        .collect();
+    messages.push(user_message.clone());
+
+    let completion = completions::complete(&db, &app, messages)?;
+
+    // only persist the turn once the model has replied so a failure can simply be retried, the
+    // question and reply are written together so a turn is never left unanswered
+    let reply = GenerationMessage {
+        role: "assistant".to_string(),
+        content: completion.output.clone(),
+    };
+    db.transaction(|| {
+        db.insert_completion(&completion)?;
+        db.insert_conversation_message(id, &user_message)?;
+        db.insert_conversation_message(id, &reply)
+    })?;
+
+    let response = ConversationReply {
+        conversation_id: id,
+        message: reply,
+        usage: completion.usage,
+        dropped_messages: completion.dropped_messages,
+        sources: completion.sources,
+    };
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&response)?,
+    ))
+}
+
+impl Db {
+    pub fn insert_conversation(&self, app_name: &str, title: &str) -> Result<i64> {
+        let result = self.conn.execute(
+            "INSERT INTO conversations (app_name, title) VALUES (?, ?) RETURNING id",
+            &[ValueParam::Text(app_name), ValueParam::Text(title)],
+        )?;
+
+        result
+            .rows
+            .first()
+            .and_then(|r| r.get::<i64>(0))
+            .context("insert did not return the conversation id")
+    }
+
+    pub fn list_conversations(
+        &self,
+        app_name: &str,
+        offset: i64,
+        limit: i64,
+    ) -> Result<Vec<Conversation>> {
+        let result = self.conn.execute(
+            "SELECT * FROM conversations WHERE app_name = ? ORDER BY updated_at DESC, id DESC LIMIT ? OFFSET ?",
+            &[
+                ValueParam::Text(app_name),
+                ValueParam::Integer(limit),
+                ValueParam::Integer(offset),
+            ],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        Ok(result
+            .rows
+            .iter()
+            .map(|r| Conversation {
+                id: r.get::<i64>(col_map["id"]).unwrap_or_default(),
+                created_at: r
+                    .get::<&str>(col_map["created_at"])
+                    .unwrap_or_default()
+                    .to_string(),
+                updated_at: r
+                    .get::<&str>(col_map["updated_at"])
+                    .unwrap_or_default()
+                    .to_string(),
+                app_name: r
+                    .get::<&str>(col_map["app_name"])
+                    .unwrap_or_default()
+                    .to_string(),
+                title: r
+                    .get::<&str>(col_map["title"])
+                    .unwrap_or_default()
+                    .to_string(),
+                messages: Vec::new(),
+            })
+            .collect())
+    }
+
+    pub fn get_conversation(&self, app_name: &str, id: i64) -> Result<Conversation> {
+        let result = self.conn.execute(
+            "SELECT * FROM conversations WHERE app_name = ? AND id = ?",
+            &[ValueParam::Text(app_name), ValueParam::Integer(id)],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+        let row = result
+            .rows
+            .first()
+            .ok_or_else(|| ApiError::NotFound(format!("Conversation {} not found", id)))?;
+
+        Ok(Conversation {
+            id,
+            created_at: row
+                .get::<&str>(col_map["created_at"])
+                .unwrap_or_default()
+                .to_string(),
+            updated_at: row
+                .get::<&str>(col_map["updated_at"])
+                .unwrap_or_default()
+                .to_string(),
+            app_name: app_name.to_string(),
+            title: row
+                .get::<&str>(col_map["title"])
+                .unwrap_or_default()
+                .to_string(),
+            messages: self.list_conversation_messages(id)?,
+        })
+    }
+
+    pub fn insert_conversation_message(
+        &self,
+        conversation_id: i64,
+        message: &GenerationMessage,
+    ) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO conversation_messages (conversation_id, role, content) VALUES (?, ?, ?)",
+            &[
+                ValueParam::Integer(conversation_id),
+                ValueParam::Text(&message.role),
+                ValueParam::Text(&message.content),
+            ],
+        )?;
+        self.conn.execute(
+            "UPDATE conversations SET updated_at = CURRENT_TIMESTAMP WHERE id = ?",
+            &[ValueParam::Integer(conversation_id)],
+        )?;
+        Ok(())
+    }
+
+    fn list_conversation_messages(&self, conversation_id: i64) -> Result<Vec<ConversationMessage>> {
+        let result = self.conn.execute(
+            "SELECT * FROM conversation_messages WHERE conversation_id = ? ORDER BY id ASC",
+            &[ValueParam::Integer(conversation_id)],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        Ok(result
+            .rows
+            .iter()
+            .map(|r| ConversationMessage {
+                id: r.get::<i64>(col_map["id"]).unwrap_or_default(),
+                created_at: r
+                    .get::<&str>(col_map["created_at"])
+                    .unwrap_or_default()
+                    .to_string(),
+                role: r
+                    .get::<&str>(col_map["role"])
+                    .unwrap_or_default()
+                    .to_string(),
+                content: r
+                    .get::<&str>(col_map["content"])
+                    .unwrap_or_default()
+                    .to_string(),
+            })
+            .collect())
+    }
+
+    pub fn delete_conversations(&self, app_name: &str) -> Result<()> {
+        // the explicit delete is required, Db::new doesn't enable foreign keys so the schema's
+        // ON DELETE CASCADE never runs
+        self.conn.execute(
+            "DELETE FROM conversation_messages
+      WHERE conversation_id IN (SELECT id FROM conversations WHERE app_name = ?)",
+            &[ValueParam::Text(app_name)],
+        )?;
+        self.conn.execute(
+            "DELETE FROM conversations WHERE app_name = ?",
+            &[ValueParam::Text(app_name)],
+        )?;
+        Ok(())
+    }
+}
@@ -0,0 +1,607 @@
+use std::time::Instant;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use shared::{parse_json, validation::Validator, ApiError, GenerationMessage, GenerationUsage};
+use spin_sdk::{
+    http::{Params, Request, Response},
+    sqlite::ValueParam,
+};
+
+use crate::{completions, data::Db, json_response, model::App, route_params, validate_messages};
+
+const MAX_CASES_PER_RUN: usize = 100;
+
+/// A check made against a completion's output, outputs are compared after trimming surrounding
+/// whitespace.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Assertion {
+    Contains {
+        value: String,
+    },
+    NotContains {
+        value: String,
+    },
+    Regex {
+        pattern: String,
+    },
+    ExactMatch {
+        value: String,
+    },
+    JsonParses,
+    JsonSchema {
+        schema: serde_json::Value,
+    },
+    /// Maximum length of the output in characters.
+    MaxLength {
+        value: usize,
+    },
+}
+
+/// Inputs and expectations for one eval case. The app's system prompt is prepended so
+/// `messages` should only hold the user and assistant turns.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct EvalCase {
+    #[serde(default)]
+    pub id: i64,
+    #[serde(default)]
+    pub created_at: String,
+    #[serde(default)]
+    pub app_name: String,
+    pub name: String,
+    pub messages: Vec<GenerationMessage>,
+    pub assertions: Vec<Assertion>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct AssertionResult {
+    pub assertion: Assertion,
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct CaseResult {
+    pub case_id: i64,
+    pub name: String,
+    pub passed: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// Set when the completion itself failed, in which case no assertions were checked.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    pub assertions: Vec<AssertionResult>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub usage: Option<GenerationUsage>,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EvalRun {
+    pub app_name: String,
+    pub total: usize,
+    pub passed: usize,
+    pub failed: usize,
+    /// Fraction of cases that passed, 0 when there are no cases.
+    pub score: f64,
+    pub cases: Vec<CaseResult>,
+}
+
+impl Assertion {
+    /// Compile errors for patterns and schemas, checked when a case is saved.
+    fn validate(&self) -> Option<String> {
+        match self {
+            Assertion::Regex { pattern } => regex::Regex::new(pattern)
+                .err()
+                .map(|e| format!("invalid regex: {}", e)),
+            Assertion::JsonSchema { schema } => jsonschema::JSONSchema::compile(schema)
+                .err()
+                .map(|e| format!("invalid JSON schema: {}", e)),
+            _ => None,
+        }
+    }
+
+    pub fn check(&self, output: &str) -> AssertionResult {
+        let output = output.trim();
+        let failure = match self {
+            Assertion::Contains { value } => (!output.contains(value.as_str()))
+                .then(|| format!("output does not contain {:?}", value)),
+            Assertion::NotContains { value } => output
+                .contains(value.as_str())
+                .then(|| format!("output contains {:?}", value)),
+            Assertion::Regex { pattern } => match regex::Regex::new(pattern) {
+                Ok(re) => {
+                    (!re.is_match(output)).then(|| format!("output does not match /{}/", pattern))
+                }
+                Err(e) => Some(format!("invalid regex: {}", e)),
+            },
+            Assertion::ExactMatch { value } => {
+                (output != value.trim()).then(|| format!("output is not exactly {:?}", value))
+            }
+            Assertion::JsonParses => serde_json::from_str::<serde_json::Value>(output)
+                .err()
+                .map(|e| format!("output is not valid JSON: {}", e)),
+            Assertion::JsonSchema { schema } => {
+                match (
+                    jsonschema::JSONSchema::compile(schema),
+                    serde_json::from_str::<serde_json::Value>(output),
+                ) {
+                    (Err(e), _) => Some(format!("invalid JSON schema: {}", e)),
+                    (_, Err(e)) => Some(format!("output is not valid JSON: {}", e)),
+                    (Ok(compiled), Ok(instance)) => match compiled.validate(&instance) {
+                        Ok(()) => None,
+                        Err(errors) => Some(
+                            errors
+                                .map(|e| format!("{} at '{}'", e, e.instance_path))
+                                .collect::<Vec<_>>()
+                                .join("; "),
+                        ),
+                    },
+                }
+            }
+            Assertion::MaxLength { value } => {
+                let length = output.chars().count();
+                (length > *value).then(|| {
+                    format!(
+                        "output is {} characters, expected at most {}",
+                        length, value
+                    )
+                })
+            }
+        };
+
+        AssertionResult {
+            assertion: self.clone(),
+            passed: failure.is_none(),
+            message: failure,
+        }
+    }
+}
+
+impl EvalCase {
+    fn validate(&self) -> Result<(), ApiError> {
+        let mut v = Validator::new();
+        v.check("name", !self.name.trim().is_empty(), "must not be empty");
+        v.check(
+            "messages",
+            !self.messages.is_empty(),
+            "must contain at least one message",
+        );
+        v.check(
+            "assertions",
+            !self.assertions.is_empty(),
+            "must contain at least one assertion",
+        );
+        validate_messages(&mut v, &self.messages);
+        for (i, assertion) in self.assertions.iter().enumerate() {
+            if let Some(message) = assertion.validate() {
+                v.check(&format!("assertions[{}]", i), false, &message);
+            }
+        }
+        v.finish()
+    }
+}
+
+pub fn list_eval_cases(_req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let cases = db.list_eval_cases(&app.name)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&cases)?,
+    ))
+}
+
+pub fn create_eval_case(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let req_body = match req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let new_case = parse_json::<EvalCase>(&req_body)?;
+    new_case.validate()?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let id = db.insert_eval_case(&app.name, &new_case)?;
+    let case = db.get_eval_case(&app.name, id)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::CREATED)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(
+            http::header::LOCATION,
+            format!("/api/apps/{}/evals/{}", app.name, id),
+        )
+        .body(Some(serde_json::to_vec(&case)?.into()))
+        .unwrap())
+}
+
+pub fn get_eval_case(_req: Request, params: Params) -> Result<Response> {
+    let (name, id) = route_params(&params, "id", "Eval case id")?;
+
+    let db = Db::new()?;
+    let case = db.get_eval_case(name, id)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&case)?,
+    ))
+}
+
+pub fn update_eval_case(req: Request, params: Params) -> Result<Response> {
+    let (name, id) = route_params(&params, "id", "Eval case id")?;
+    let req_body = match req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let case = parse_json::<EvalCase>(&req_body)?;
+    case.validate()?;
+
+    let db = Db::new()?;
+    db.update_eval_case(name, id, &case)?;
+    let case = db.get_eval_case(name, id)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&case)?,
+    ))
+}
+
+pub fn delete_eval_case(_req: Request, params: Params) -> Result<Response> {
+    let (name, id) = route_params(&params, "id", "Eval case id")?;
+
+    let db = Db::new()?;
+    db.delete_eval_case(name, id)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .body(None)
+        .unwrap())
+}
+
+/// Runs a single case, a failed completion is reported on the case rather than failing the run.
+pub fn run_case(db: &Db, app: &App, case: &EvalCase) -> CaseResult {
+    let started = Instant::now();
+    let completion = completions::complete(db, app, case.messages.clone());
+    let duration_ms = started.elapsed().as_millis() as u64;
+
+    match completion {
+        Ok(completion) => {
+            let assertions: Vec<AssertionResult> = case
+                .assertions
+                .iter()
+                .map(|a| a.check(&completion.output))
+                .collect();
+            CaseResult {
+                case_id: case.id,
+                name: case.name.clone(),
+                passed: assertions.iter().all(|a| a.passed),
+                output: Some(completion.output),
+                error: None,
+                assertions,
+                usage: Some(completion.usage),
+                duration_ms,
+            }
+        }
+        Err(e) => CaseResult {
+            case_id: case.id,
+            name: case.name.clone(),
+            passed: false,
+            output: None,
+            error: Some(ApiError::from(&e).to_string()),
+            assertions: Vec::new(),
+            usage: None,
+            duration_ms,
+        },
+    }
+}
+
+pub fn summarize(app_name: &str, cases: Vec<CaseResult>) -> EvalRun {
+    let total = cases.len();
+    let passed = cases.iter().filter(|c| c.passed).count();
+    EvalRun {
+        app_name: app_name.to_string(),
+        total,
+        passed,
+        failed: total - passed,
+        score: match total {
+            0 => 0.0,
+            _ => passed as f64 / total as f64,
+        },
+        cases,
+    }
+}
+
+/// Runs every eval case of the app through the same completion path as `/completions`.
+pub fn run_evals(_req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let cases = db.list_eval_cases(&app.name)?;
+    if cases.len() > MAX_CASES_PER_RUN {
+        return Err(ApiError::Unprocessable(format!(
+            "App has {} eval cases, at most {} can be run in one request",
+            cases.len(),
+            MAX_CASES_PER_RUN
+        ))
+        .into());
+    }
+
+    let results = cases.iter().map(|c| run_case(&db, &app, c)).collect();
+    let run = summarize(&app.name, results);
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&run)?,
+    ))
+}
+
+impl Db {
+    pub fn insert_eval_case(&self, app_name: &str, case: &EvalCase) -> Result<i64> {
+        let messages = serde_json::to_string(&case.messages)?;
+        let assertions = serde_json::to_string(&case.assertions)?;
+
+        let result = self.conn.execute(
+            "INSERT INTO eval_cases (app_name, name, messages, assertions)
+      VALUES (?, ?, ?, ?)
+      RETURNING id",
+            &[
+                ValueParam::Text(app_name),
+                ValueParam::Text(&case.name),
+                ValueParam::Text(&messages),
+                ValueParam::Text(&assertions),
+            ],
+        )?;
+
+        result
+            .rows
+            .first()
+            .and_then(|r| r.get::<i64>(0))
+            .context("insert did not return the eval case id")
+    }
+
+    pub fn update_eval_case(&self, app_name: &str, id: i64, case: &EvalCase) -> Result<()> {
+        let messages = serde_json::to_string(&case.messages)?;
+        let assertions = serde_json::to_string(&case.assertions)?;
+
+        let result = self.conn.execute(
+            "UPDATE eval_cases SET name = ?, messages = ?, assertions = ?
+      WHERE app_name = ? AND id = ?
+      RETURNING id",
+            &[
+                ValueParam::Text(&case.name),
+                ValueParam::Text(&messages),
+                ValueParam::Text(&assertions),
+                ValueParam::Text(app_name),
+                ValueParam::Integer(id),
+            ],
+        )?;
+
+        if result.rows.is_empty() {
+            return Err(ApiError::NotFound(format!("Eval case {} not found", id)).into());
+        }
+        Ok(())
+    }
+
+    pub fn list_eval_cases(&self, app_name: &str) -> Result<Vec<EvalCase>> {
+        self.select_eval_cases(
+            "SELECT * FROM eval_cases WHERE app_name = ? ORDER BY id ASC",
+            &[ValueParam::Text(app_name)],
+        )
+    }
+
+    pub fn get_eval_case(&self, app_name: &str, id: i64) -> Result<EvalCase> {
+        self.select_eval_cases(
+            "SELECT * FROM eval_cases WHERE app_name = ? AND id = ?",
+            &[ValueParam::Text(app_name), ValueParam::Integer(id)],
+        )?
+        .pop()
+        .ok_or_else(|| ApiError::NotFound(format!("Eval case {} not found", id)).into())
+    }
+
+    pub fn delete_eval_case(&self, app_name: &str, id: i64) -> Result<()> {
+        let result = self.conn.execute(
+            "DELETE FROM eval_cases WHERE app_name = ? AND id = ? RETURNING id",
+            &[ValueParam::Text(app_name), ValueParam::Integer(id)],
+        )?;
+        if result.rows.is_empty() {
+            return Err(ApiError::NotFound(format!("Eval case {} not found", id)).into());
+        }
+        Ok(())
+    }
+
+    pub fn delete_eval_cases(&self, app_name: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM eval_cases WHERE app_name = ?",
+            &[ValueParam::Text(app_name)],
+        )?;
+        Ok(())
+    }
+
+    fn select_eval_cases(&self, query: &str, params: &[ValueParam]) -> Result<Vec<EvalCase>> {
+        let result = self.conn.execute(query, params)?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        result
+            .rows
+            .iter()
+            .map(|r| {
+                let messages = r.get::<&str>(col_map["messages"]).unwrap_or("[]");
+                let assertions = r.get::<&str>(col_map["assertions"]).unwrap_or("[]");
+
+                Ok(EvalCase {
+                    id: r.get::<i64>(col_map["id"]).unwrap_or_default(),
+                    created_at: r
+                        .get::<&str>(col_map["created_at"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    app_name: r
+                        .get::<&str>(col_map["app_name"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    name: r
+                        .get::<&str>(col_map["name"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    messages: serde_json::from_str(messages)?,
+                    assertions: serde_json::from_str(assertions)?,
+                })
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn passes(assertion: Assertion, output: &str) -> bool {
+        let result = assertion.check(output);
+        assert_eq!(result.passed, result.message.is_none());
+        result.passed
+    }
+
+    fn case(assertions: Vec<Assertion>) -> EvalCase {
+        EvalCase {
+            id: 0,
+            created_at: String::new(),
+            app_name: String::new(),
+            name: "case".to_string(),
+            messages: vec![GenerationMessage {
+                role: "user".to_string(),
+                content: "hello".to_string(),
+            }],
+            assertions,
+        }
+    }
+
+    fn invalid_fields(result: Result<(), ApiError>) -> Vec<String> {
+        match result {
+            Err(ApiError::Validation(errors)) => errors.into_iter().map(|e| e.field).collect(),
+            other => panic!("expected a validation error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn contains() {
+        let value = "Paris".to_string();
+        assert!(passes(
+            Assertion::Contains {
+                value: value.clone()
+            },
+            "It is Paris."
+        ));
+        assert!(!passes(Assertion::Contains { value }, "It is Lyon."));
+    }
+
+    #[test]
+    fn not_contains() {
+        let value = "sorry".to_string();
+        assert!(passes(
+            Assertion::NotContains {
+                value: value.clone()
+            },
+            "Sure, here it is."
+        ));
+        assert!(!passes(
+            Assertion::NotContains { value },
+            "I'm sorry, I can't."
+        ));
+    }
+
+    #[test]
+    fn regex() {
+        let pattern = r"^\d{3}-\d{4}$".to_string();
+        assert!(passes(
+            Assertion::Regex {
+                pattern: pattern.clone()
+            },
+            " 555-1234\n"
+        ));
+        assert!(!passes(Assertion::Regex { pattern }, "call 555-1234"));
+    }
+
+    #[test]
+    fn exact_match_ignores_surrounding_whitespace() {
+        assert!(passes(
+            Assertion::ExactMatch {
+                value: "positive ".to_string()
+            },
+            "\npositive"
+        ));
+        assert!(!passes(
+            Assertion::ExactMatch {
+                value: "positive".to_string()
+            },
+            "Positive"
+        ));
+    }
+
+    #[test]
+    fn json_parses() {
+        assert!(passes(
+            Assertion::JsonParses,
+            r#"{"sentiment": "positive"}"#
+        ));
+        assert!(!passes(Assertion::JsonParses, "sentiment: positive"));
+    }
+
+    #[test]
+    fn json_schema() {
+        let schema = serde_json::json!({
+            "type": "object",
+            "properties": { "sentiment": { "enum": ["positive", "negative"] } },
+            "required": ["sentiment"]
+        });
+        let assertion = || Assertion::JsonSchema {
+            schema: schema.clone(),
+        };
+        assert!(passes(assertion(), r#"{"sentiment": "negative"}"#));
+        assert!(!passes(assertion(), r#"{"sentiment": "neutral"}"#));
+        assert!(!passes(assertion(), "not json"));
+    }
+
+    #[test]
+    fn max_length_counts_characters() {
+        assert!(passes(Assertion::MaxLength { value: 5 }, " héllo "));
+        assert!(!passes(Assertion::MaxLength { value: 4 }, "héllo"));
+    }
+
+    #[test]
+    fn invalid_regex_and_schema_are_rejected_when_saved() {
+        let valid = case(vec![Assertion::Regex {
+            pattern: "a+".to_string(),
+        }]);
+        assert!(valid.validate().is_ok());
+
+        let invalid = case(vec![
+            Assertion::Contains {
+                value: "a".to_string(),
+            },
+            Assertion::Regex {
+                pattern: "(unclosed".to_string(),
+            },
+            Assertion::JsonSchema {
+                schema: serde_json::json!({ "type": "no-such-type" }),
+            },
+        ]);
+        assert_eq!(
+            invalid_fields(invalid.validate()),
+            vec!["assertions[1]", "assertions[2]"]
+        );
+    }
+}
@@ -0,0 +1,617 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use shared::{parse_json, validation::Validator, ApiError, GenerationMessage, GenerationResponse};
+use spin_sdk::{
+    http::{Params, Request, Response},
+    sqlite::{RowResult, ValueParam},
+};
+
+use crate::{
+    batch, completions,
+    data::Db,
+    evals::{self, EvalCase},
+    json_response,
+    model::App,
+    route_id,
+};
+
+const MAX_JOB_ITEMS: usize = 10_000;
+const DEFAULT_WORK_LIMIT: i64 = 5;
+const MAX_WORK_LIMIT: i64 = 50;
+/// Items claimed longer ago than this belong to a worker that didn't finish, they are handed
+/// out again on the next call to the worker.
+const CLAIM_TIMEOUT_MINUTES: i64 = 15;
+/// Times an item is claimed before it's failed, so an item that crashes the worker isn't retried
+/// forever.
+const MAX_ATTEMPTS: i64 = 3;
+
+const PENDING: &str = "pending";
+const RUNNING: &str = "running";
+const COMPLETED: &str = "completed";
+const FAILED: &str = "failed";
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum JobKind {
+    Completion,
+    Batch,
+    Eval,
+}
+
+impl JobKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            JobKind::Completion => "completion",
+            JobKind::Batch => "batch",
+            JobKind::Eval => "eval",
+        }
+    }
+
+    pub fn parse(kind: &str) -> Option<Self> {
+        match kind {
+            "completion" => Some(JobKind::Completion),
+            "batch" => Some(JobKind::Batch),
+            "eval" => Some(JobKind::Eval),
+            _ => None,
+        }
+    }
+}
+
+/// Work to queue, the app is snapshotted when the job is created so later edits to the app
+/// don't change the results part way through.
+#[derive(Debug, Deserialize)]
+#[serde(tag = "kind", rename_all = "snake_case")]
+pub enum JobRequest {
+    Completion {
+        app_name: String,
+        messages: Vec<GenerationMessage>,
+    },
+    /// JSONL in the same format as `POST /api/apps/:name/batch`, one item per non-empty line.
+    Batch { app_name: String, input: String },
+    /// One item per eval case the app has when the job is created.
+    Eval { app_name: String },
+}
+
+impl JobRequest {
+    fn kind(&self) -> JobKind {
+        match self {
+            JobRequest::Completion { .. } => JobKind::Completion,
+            JobRequest::Batch { .. } => JobKind::Batch,
+            JobRequest::Eval { .. } => JobKind::Eval,
+        }
+    }
+
+    fn app_name(&self) -> &str {
+        match self {
+            JobRequest::Completion { app_name, .. }
+            | JobRequest::Batch { app_name, .. }
+            | JobRequest::Eval { app_name } => app_name,
+        }
+    }
+}
+
+/// The input of a batch job item.
+#[derive(Debug, Serialize, Deserialize)]
+struct BatchItem {
+    line: usize,
+    text: String,
+}
+
+#[derive(Debug, Default, Serialize)]
+pub struct JobProgress {
+    pub total: i64,
+    pub pending: i64,
+    pub running: i64,
+    pub completed: i64,
+    pub failed: i64,
+}
+
+/// The outcome of one item. `result` has the same shape as the synchronous endpoint's response
+/// for the kind of job: a completion response, a batch line result or an eval case result.
+#[derive(Debug, Serialize)]
+pub struct JobItem {
+    pub index: i64,
+    pub status: String,
+    /// Times a worker has claimed the item.
+    pub attempts: i64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<serde_json::Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// A job is `completed` once none of its items are pending or running, items that failed are
+/// counted in `progress.failed` and carry their own error.
+#[derive(Debug, Serialize)]
+pub struct Job {
+    pub id: i64,
+    pub created_at: String,
+    pub updated_at: String,
+    pub kind: String,
+    pub app_name: String,
+    pub status: String,
+    pub progress: JobProgress,
+    /// Eval jobs only, the fraction of finished cases that passed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub results: Option<Vec<JobItem>>,
+}
+
+#[derive(Debug, Deserialize)]
+struct JobQuery {
+    /// Set to false to poll for progress without fetching every result.
+    results: Option<bool>,
+}
+
+#[derive(Debug, Deserialize)]
+struct WorkQuery {
+    limit: Option<i64>,
+}
+
+#[derive(Debug, Serialize)]
+struct WorkSummary {
+    processed: usize,
+    /// Items still waiting for a worker across all jobs.
+    remaining: i64,
+    jobs: Vec<i64>,
+}
+
+/// An item handed to a worker along with what it needs to run it.
+struct ClaimedItem {
+    id: i64,
+    job_id: i64,
+    kind: String,
+    app_snapshot: String,
+    input: String,
+}
+
+/// Serialized inputs for each item of the job.
+fn job_inputs(db: &Db, app: &App, req: &JobRequest) -> Result<Vec<String>> {
+    let inputs = match req {
+        JobRequest::Completion { messages, .. } => {
+            let mut v = Validator::new();
+            v.check(
+                "messages",
+                !messages.is_empty(),
+                "must contain at least one message",
+            );
+            v.finish()?;
+            vec![serde_json::to_string(messages)?]
+        }
+        JobRequest::Batch { input, .. } => batch::input_lines(input)?
+            .into_iter()
+            .map(|(line, text)| {
+                serde_json::to_string(&BatchItem {
+                    line,
+                    text: text.to_string(),
+                })
+            })
+            .collect::<serde_json::Result<_>>()?,
+        JobRequest::Eval { .. } => {
+            let cases = db.list_eval_cases(&app.name)?;
+            if cases.is_empty() {
+                return Err(ApiError::Unprocessable(format!(
+                    "App '{}' has no eval cases",
+                    app.name
+                ))
+                .into());
+            }
+            cases
+                .iter()
+                .map(serde_json::to_string)
+                .collect::<serde_json::Result<_>>()?
+        }
+    };
+
+    if inputs.len() > MAX_JOB_ITEMS {
+        return Err(ApiError::Unprocessable(format!(
+            "Job has {} items, at most {} can be queued in one job",
+            inputs.len(),
+            MAX_JOB_ITEMS
+        ))
+        .into());
+    }
+    Ok(inputs)
+}
+
+/// Queues the work and responds straight away, call `POST /api/jobs/work` to run it.
+pub fn create_job(req: Request, _params: Params) -> Result<Response> {
+    let req_body = match req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let job_req = parse_json::<JobRequest>(&req_body)?;
+
+    let db = Db::new()?;
+    let app = db.get(job_req.app_name())?;
+    let inputs = job_inputs(&db, &app, &job_req)?;
+    let id = db.insert_job(job_req.kind(), &app, &inputs)?;
+    let job = db.get_job(id, false)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::ACCEPTED)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::LOCATION, format!("/api/jobs/{}", id))
+        .body(Some(serde_json::to_vec(&job)?.into()))
+        .unwrap())
+}
+
+pub fn get_job(req: Request, params: Params) -> Result<Response> {
+    let id = route_id(&params, "id", "Job id")?;
+    let job_query: JobQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+
+    let db = Db::new()?;
+    let job = db.get_job(id, job_query.results.unwrap_or(true))?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&job)?,
+    ))
+}
+
+/// Runs one item, the error is set when the item should be counted as failed.
+fn run_item(db: &Db, item: &ClaimedItem) -> Result<(serde_json::Value, Option<String>)> {
+    let kind =
+        JobKind::parse(&item.kind).with_context(|| format!("unknown job kind '{}'", item.kind))?;
+    let app: App = serde_json::from_str(&item.app_snapshot)?;
+
+    Ok(match kind {
+        JobKind::Completion => {
+            let messages: Vec<GenerationMessage> = serde_json::from_str(&item.input)?;
+            let completion = completions::complete(db, &app, messages)?;
+            db.insert_completion(&completion)?;
+            let response = GenerationResponse {
+                messages: vec![GenerationMessage {
+                    role: "assistant".to_string(),
+                    content: completion.output,
+                }],
+                usage: completion.usage,
+                dropped_messages: completion.dropped_messages,
+                sources: completion.sources,
+            };
+            (serde_json::to_value(&response)?, None)
+        }
+        JobKind::Batch => {
+            let input: BatchItem = serde_json::from_str(&item.input)?;
+            let result = batch::run_line(db, &app, input.line, &input.text);
+            let error = result.error.as_ref().map(|e| e.message.clone());
+            (serde_json::to_value(&result)?, error)
+        }
+        JobKind::Eval => {
+            let case: EvalCase = serde_json::from_str(&item.input)?;
+            let result = evals::run_case(db, &app, &case);
+            let error = result.error.clone();
+            (serde_json::to_value(&result)?, error)
+        }
+    })
+}
+
+/// Claims up to `limit` pending items, oldest first across all jobs, and runs them. Meant to be
+/// called repeatedly, by a scheduler or the client, until `remaining` is 0.
+pub fn work(req: Request, _params: Params) -> Result<Response> {
+    let work_query: WorkQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+    let limit = work_query
+        .limit
+        .unwrap_or(DEFAULT_WORK_LIMIT)
+        .clamp(1, MAX_WORK_LIMIT);
+
+    let db = Db::new()?;
+    let items = db.claim_job_items(limit)?;
+
+    let mut jobs = Vec::new();
+    for item in &items {
+        match run_item(&db, item) {
+            Ok((result, error)) => {
+                let result = serde_json::to_string(&result)?;
+                db.finish_job_item(item, Some(&result), error.as_deref())?;
+            }
+            Err(e) => {
+                let error = ApiError::from(&e).to_string();
+                db.finish_job_item(item, None, Some(&error))?;
+            }
+        }
+        if !jobs.contains(&item.job_id) {
+            jobs.push(item.job_id);
+        }
+    }
+
+    let summary = WorkSummary {
+        processed: items.len(),
+        remaining: db.count_pending_job_items()?,
+        jobs,
+    };
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&summary)?,
+    ))
+}
+
+impl Db {
+    pub fn insert_job(&self, kind: JobKind, app: &App, inputs: &[String]) -> Result<i64> {
+        let app_snapshot = serde_json::to_string(app)?;
+
+        self.transaction(|| {
+            let result = self.conn.execute(
+                "INSERT INTO jobs (kind, app_name, app_snapshot) VALUES (?, ?, ?) RETURNING id",
+                &[
+                    ValueParam::Text(kind.as_str()),
+                    ValueParam::Text(&app.name),
+                    ValueParam::Text(&app_snapshot),
+                ],
+            )?;
+            let id = result
+                .rows
+                .first()
+                .and_then(|r| r.get::<i64>(0))
+                .context("insert did not return the job id")?;
+
+            for (i, input) in inputs.iter().enumerate() {
+                self.conn.execute(
+                    "INSERT INTO job_items (job_id, item_index, input) VALUES (?, ?, ?)",
+                    &[
+                        ValueParam::Integer(id),
+                        ValueParam::Integer(i as i64),
+                        ValueParam::Text(input),
+                    ],
+                )?;
+            }
+            Ok(id)
+        })
+    }
+
+    pub fn get_job(&self, id: i64, with_results: bool) -> Result<Job> {
+        let result = self.conn.execute(
+            "SELECT id, created_at, updated_at, kind, app_name, status FROM jobs WHERE id = ?",
+            &[ValueParam::Integer(id)],
+        )?;
+        let col_map = Self::get_column_lookup(&result.columns);
+        let row = result
+            .rows
+            .first()
+            .ok_or_else(|| ApiError::NotFound(format!("Job {} not found", id)))?;
+        let text = |col: &str| {
+            row.get::<&str>(col_map[col])
+                .unwrap_or_default()
+                .to_string()
+        };
+
+        let mut job = Job {
+            id,
+            created_at: text("created_at"),
+            updated_at: text("updated_at"),
+            kind: text("kind"),
+            app_name: text("app_name"),
+            status: text("status"),
+            progress: JobProgress::default(),
+            score: None,
+            results: None,
+        };
+
+        let counts = self.conn.execute(
+            "SELECT status, COUNT(*) AS count FROM job_items WHERE job_id = ? GROUP BY status",
+            &[ValueParam::Integer(id)],
+        )?;
+        for r in &counts.rows {
+            let count = r.get::<i64>(1).unwrap_or_default();
+            job.progress.total += count;
+            match r.get::<&str>(0).unwrap_or_default() {
+                PENDING => job.progress.pending = count,
+                RUNNING => job.progress.running = count,
+                COMPLETED => job.progress.completed = count,
+                FAILED => job.progress.failed = count,
+                _ => {}
+            }
+        }
+
+        if job.kind == JobKind::Eval.as_str() {
+            let passed = self.conn.execute(
+                "SELECT COUNT(*) FROM job_items
+      WHERE job_id = ? AND json_extract(result, '$.passed') = 1",
+                &[ValueParam::Integer(id)],
+            )?;
+            let passed = passed
+                .rows
+                .first()
+                .and_then(|r| r.get::<i64>(0))
+                .unwrap_or_default();
+            let finished = job.progress.completed + job.progress.failed;
+            job.score = Some(match finished {
+                0 => 0.0,
+                _ => passed as f64 / finished as f64,
+            });
+        }
+
+        if with_results {
+            job.results = Some(self.list_job_items(id)?);
+        }
+        Ok(job)
+    }
+
+    fn list_job_items(&self, job_id: i64) -> Result<Vec<JobItem>> {
+        let result = self.conn.execute(
+            "SELECT item_index, status, attempts, result, error FROM job_items
+      WHERE job_id = ? ORDER BY item_index ASC",
+            &[ValueParam::Integer(job_id)],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        result
+            .rows
+            .iter()
+            .map(|r| {
+                let item_result = match r.get::<&str>(col_map["result"]) {
+                    Some(json) => Some(serde_json::from_str(json)?),
+                    None => None,
+                };
+                Ok(JobItem {
+                    index: r.get::<i64>(col_map["item_index"]).unwrap_or_default(),
+                    status: r
+                        .get::<&str>(col_map["status"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    attempts: r.get::<i64>(col_map["attempts"]).unwrap_or_default(),
+                    result: item_result,
+                    error: r.get::<&str>(col_map["error"]).map(str::to_string),
+                })
+            })
+            .collect()
+    }
+
+    /// Marks up to `limit` pending items as running in one transaction so concurrent workers
+    /// never run the same item. Stale claims go back to pending, or are failed once the item has
+    /// been claimed `MAX_ATTEMPTS` times.
+    fn claim_job_items(&self, limit: i64) -> Result<Vec<ClaimedItem>> {
+        self.transaction(|| {
+            let timeout = format!("-{} minutes", CLAIM_TIMEOUT_MINUTES);
+            let gave_up = self.conn.execute(
+                "UPDATE job_items SET status = ?, error = ?, claimed_at = NULL
+      WHERE status = ? AND claimed_at < datetime('now', ?) AND attempts >= ?
+      RETURNING job_id",
+                &[
+                    ValueParam::Text(FAILED),
+                    ValueParam::Text(&format!(
+                        "Gave up after {} attempts, the worker never finished the item",
+                        MAX_ATTEMPTS
+                    )),
+                    ValueParam::Text(RUNNING),
+                    ValueParam::Text(&timeout),
+                    ValueParam::Integer(MAX_ATTEMPTS),
+                ],
+            )?;
+            let mut gave_up_jobs: Vec<i64> = gave_up
+                .rows
+                .iter()
+                .filter_map(|r| r.get::<i64>(0))
+                .collect();
+            gave_up_jobs.sort_unstable();
+            gave_up_jobs.dedup();
+            for job_id in gave_up_jobs {
+                self.update_job_status(job_id)?;
+            }
+
+            self.conn.execute(
+                "UPDATE job_items SET status = ?, claimed_at = NULL
+      WHERE status = ? AND claimed_at < datetime('now', ?)",
+                &[
+                    ValueParam::Text(PENDING),
+                    ValueParam::Text(RUNNING),
+                    ValueParam::Text(&timeout),
+                ],
+            )?;
+
+            let result = self.conn.execute(
+                "SELECT i.id, i.job_id, i.input, j.kind, j.app_snapshot
+      FROM job_items i
+      JOIN jobs j ON j.id = i.job_id
+      WHERE i.status = ?
+      ORDER BY i.id ASC
+      LIMIT ?",
+                &[ValueParam::Text(PENDING), ValueParam::Integer(limit)],
+            )?;
+            let col_map = Self::get_column_lookup(&result.columns);
+            let text = |r: &RowResult, col: &str| {
+                r.get::<&str>(col_map[col]).unwrap_or_default().to_string()
+            };
+            let items: Vec<ClaimedItem> = result
+                .rows
+                .iter()
+                .map(|r| ClaimedItem {
+                    id: r.get::<i64>(col_map["id"]).unwrap_or_default(),
+                    job_id: r.get::<i64>(col_map["job_id"]).unwrap_or_default(),
+                    kind: text(r, "kind"),
+                    app_snapshot: text(r, "app_snapshot"),
+                    input: text(r, "input"),
+                })
+                .collect();
+
+            for item in &items {
+                self.conn.execute(
+                    "UPDATE job_items SET status = ?, claimed_at = CURRENT_TIMESTAMP,
+        attempts = attempts + 1
+      WHERE id = ?",
+                    &[ValueParam::Text(RUNNING), ValueParam::Integer(item.id)],
+                )?;
+                self.conn.execute(
+                    "UPDATE jobs SET status = ?, updated_at = CURRENT_TIMESTAMP
+      WHERE id = ? AND status = ?",
+                    &[
+                        ValueParam::Text(RUNNING),
+                        ValueParam::Integer(item.job_id),
+                        ValueParam::Text(PENDING),
+                    ],
+                )?;
+            }
+            Ok(items)
+        })
+    }
+
+    /// Records the item's outcome and completes the job once nothing is left to run.
+    fn finish_job_item(
+        &self,
+        item: &ClaimedItem,
+        result: Option<&str>,
+        error: Option<&str>,
+    ) -> Result<()> {
+        let status = match error {
+            Some(_) => FAILED,
+            None => COMPLETED,
+        };
+        self.conn.execute(
+            "UPDATE job_items SET status = ?, result = ?, error = ?, claimed_at = NULL WHERE id = ?",
+            &[
+                ValueParam::Text(status),
+                result.map_or(ValueParam::Null, ValueParam::Text),
+                error.map_or(ValueParam::Null, ValueParam::Text),
+                ValueParam::Integer(item.id),
+            ],
+        )?;
+        self.update_job_status(item.job_id)
+    }
+
+    /// Completes the job once none of its items are left to run.
+    fn update_job_status(&self, job_id: i64) -> Result<()> {
+        self.conn.execute(
+            "UPDATE jobs SET updated_at = CURRENT_TIMESTAMP,
+        status = CASE WHEN EXISTS (
+          SELECT 1 FROM job_items WHERE job_id = jobs.id AND status IN (?, ?)
+        ) THEN ? ELSE ? END
+      WHERE id = ?",
+            &[
+                ValueParam::Text(PENDING),
+                ValueParam::Text(RUNNING),
+                ValueParam::Text(RUNNING),
+                ValueParam::Text(COMPLETED),
+                ValueParam::Integer(job_id),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn count_pending_job_items(&self) -> Result<i64> {
+        let result = self.conn.execute(
+            "SELECT COUNT(*) FROM job_items WHERE status = ?",
+            &[ValueParam::Text(PENDING)],
+        )?;
+        Ok(result
+            .rows
+            .first()
+            .and_then(|r| r.get::<i64>(0))
+            .unwrap_or_default())
+    }
+
+    pub fn delete_jobs(&self, app_name: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM job_items WHERE job_id IN (SELECT id FROM jobs WHERE app_name = ?)",
+            &[ValueParam::Text(app_name)],
+        )?;
+        self.conn.execute(
+            "DELETE FROM jobs WHERE app_name = ?",
+            &[ValueParam::Text(app_name)],
+        )?;
+        Ok(())
+    }
+}
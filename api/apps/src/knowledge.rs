@@ -0,0 +1,709 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use shared::{
+    default_embedding_model, embedding_model, parse_json, validation::Validator, ApiError,
+    GenerationMessage, GenerationSource,
+};
+use spin_sdk::{
+    http::{Params, Request, Response},
+    sqlite::ValueParam,
+};
+
+use crate::{
+    api::ListQuery,
+    data::Db,
+    json_response, route_params,
+    search::{self, SearchMode, SearchResult},
+    splitting::{self, DocumentFormat, SplitOptions, SplitStrategy, TextChunk},
+};
+
+/// How many chunks are sent to the embedding model in one call.
+const EMBEDDING_BATCH_SIZE: usize = 32;
+/// How many chunks are added to the prompt for each completion.
+const RETRIEVAL_TOP_K: usize = 3;
+/// How many of the best ranked chunks are considered before dropping the dissimilar ones.
+const RETRIEVAL_CANDIDATES: usize = 20;
+/// Cosine similarity a chunk found only by the vector search needs to be added to the prompt.
+/// Fused ranks only order the chunks, every query has a nearest chunk even when nothing in the
+/// knowledge base is related to it.
+const MIN_RETRIEVAL_SIMILARITY: f32 = 0.3;
+const MAX_TITLE_LENGTH: usize = 200;
+
+/// A document in an app's knowledge base, `content` is only populated when fetching a single
+/// document.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Document {
+    pub id: i64,
+    pub created_at: String,
+    pub app_name: String,
+    pub title: String,
+    pub format: DocumentFormat,
+    pub split_options: SplitOptions,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub content: String,
+    pub chunk_count: i64,
+}
+
+/// A stored chunk as returned by the chunks endpoint, without its embedding.
+#[derive(Debug, Serialize)]
+pub struct DocumentChunk {
+    pub chunk_index: u32,
+    pub content: String,
+    pub start_offset: i64,
+    pub end_offset: i64,
+    pub metadata: serde_json::Value,
+}
+
+/// How to parse and split a document, anything left out is defaulted from the format.
+#[derive(Debug, Default, Deserialize)]
+pub struct IngestOptions {
+    pub format: Option<DocumentFormat>,
+    pub strategy: Option<SplitStrategy>,
+    pub chunk_size: Option<u32>,
+    pub overlap: Option<u32>,
+}
+
+#[derive(Debug, Deserialize)]
+struct NewDocument {
+    title: String,
+    content: String,
+    #[serde(flatten)]
+    options: IngestOptions,
+}
+
+#[derive(Debug, Deserialize)]
+struct UploadQuery {
+    title: String,
+    #[serde(flatten)]
+    options: IngestOptions,
+}
+
+pub fn embed(model: &str, texts: &[String]) -> Result<Vec<Vec<f32>>> {
+    let embedding_model = embedding_model(model)?;
+    let mut embeddings = Vec::with_capacity(texts.len());
+    for batch in texts.chunks(EMBEDDING_BATCH_SIZE) {
+        let result = spin_sdk::llm::generate_embeddings(embedding_model, batch)?;
+        embeddings.extend(result.embeddings);
+    }
+    Ok(embeddings)
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a: f32 = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b: f32 = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+// embeddings are stored as little endian f32s
+fn encode_embedding(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|f| f.to_le_bytes()).collect()
+}
+
+fn decode_embedding(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+/// Finds the chunks of the app's documents most relevant to the latest user message, using the
+/// same hybrid keyword and vector ranking as the search endpoint. No sources are returned when
+/// nothing is related, see `is_relevant`.
+pub fn retrieve(
+    db: &Db,
+    app_name: &str,
+    messages: &[GenerationMessage],
+) -> Result<Vec<GenerationSource>> {
+    let query = match messages.iter().rev().find(|m| m.role == "user") {
+        Some(m) if !m.content.trim().is_empty() => &m.content,
+        _ => return Ok(Vec::new()),
+    };
+
+    let results = search::search(
+        db,
+        app_name,
+        query,
+        SearchMode::Hybrid,
+        RETRIEVAL_CANDIDATES,
+    )?;
+    Ok(results
+        .into_iter()
+        .filter(is_relevant)
+        .take(RETRIEVAL_TOP_K)
+        .map(|r| GenerationSource {
+            document_id: r.document_id,
+            title: r.title,
+            chunk_index: r.chunk_index,
+            content: r.content,
+            score: r.score,
+        })
+        .collect())
+}
+
+/// Keyword matches are always relevant, they share terms such as identifiers or error codes with
+/// the query. Chunks only the vector search found need `MIN_RETRIEVAL_SIMILARITY`.
+fn is_relevant(result: &SearchResult) -> bool {
+    result.keyword_rank.is_some()
+        || result
+            .similarity
+            .is_some_and(|s| s >= MIN_RETRIEVAL_SIMILARITY)
+}
+
+/// Appends the retrieved chunks to the system prompt, numbered so the model can cite them.
+pub fn augment_system_prompt(system_prompt: &str, sources: &[GenerationSource]) -> String {
+    if sources.is_empty() {
+        return system_prompt.to_string();
+    }
+
+    let mut prompt = format!(
+        "{}\n\nUse the following context to answer where it is relevant, citing sources by their number.\n",
+        system_prompt
+    );
+    for (i, source) in sources.iter().enumerate() {
+        prompt.push_str(&format!(
+            "\n[{}] {}\n{}\n",
+            i + 1,
+            source.title,
+            source.content
+        ));
+    }
+    prompt
+}
+
+/// Splits, embeds and stores a document. A document with the same title is replaced, keeping
+/// its id, so re-uploading never leaves stale chunks behind. Returns whether it was created.
+fn ingest(
+    db: &Db,
+    app_name: &str,
+    title: &str,
+    content: &str,
+    options: IngestOptions,
+) -> Result<(i64, bool)> {
+    // stored normalised so chunk offsets index the stored content
+    let content = &*splitting::normalize_line_endings(content);
+    let format = options.format.unwrap_or(DocumentFormat::Text);
+    let split_options = SplitOptions::resolve(
+        format,
+        options.strategy,
+        options.chunk_size,
+        options.overlap,
+    )?;
+
+    let mut v = Validator::new();
+    v.check("title", !title.trim().is_empty(), "must not be empty");
+    v.check(
+        "title",
+        title.chars().count() <= MAX_TITLE_LENGTH,
+        &format!("must be at most {} characters", MAX_TITLE_LENGTH),
+    );
+    v.check("content", !content.trim().is_empty(), "must not be empty");
+    v.finish()?;
+
+    let chunks = splitting::split_document(content, format, &split_options)?;
+    if chunks.is_empty() {
+        return Err(
+            ApiError::Unprocessable("The document did not contain any text".to_string()).into(),
+        );
+    }
+
+    // embed before writing anything so a failed embedding call leaves the old version in place
+    let model = default_embedding_model();
+    let texts: Vec<String> = chunks.iter().map(|c| c.content.clone()).collect();
+    let embeddings = embed(&model, &texts)?;
+
+    db.transaction(|| {
+        let (id, created) = match db.find_document_id(app_name, title)? {
+            Some(id) => {
+                db.replace_document(id, content, format, &split_options)?;
+                db.delete_chunks(id)?;
+                (id, false)
+            }
+            None => (
+                db.insert_document(app_name, title, content, format, &split_options)?,
+                true,
+            ),
+        };
+        for (i, (chunk, embedding)) in chunks.iter().zip(&embeddings).enumerate() {
+            db.insert_chunk(id, app_name, i as i64, chunk, &model, embedding)?;
+        }
+        Ok((id, created))
+    })
+}
+
+fn ingested_response(db: &Db, app_name: &str, id: i64, created: bool) -> Result<Response> {
+    let document = db.get_document(app_name, id)?;
+    let status = match created {
+        true => http::StatusCode::CREATED,
+        false => http::StatusCode::OK,
+    };
+
+    Ok(http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(
+            http::header::LOCATION,
+            format!("/api/apps/{}/documents/{}", app_name, id),
+        )
+        .body(Some(serde_json::to_vec(&document)?.into()))
+        .unwrap())
+}
+
+pub fn create_document(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let req_body = match req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let new_document = parse_json::<NewDocument>(&req_body)?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let (id, created) = ingest(
+        &db,
+        &app.name,
+        &new_document.title,
+        &new_document.content,
+        new_document.options,
+    )?;
+
+    ingested_response(&db, &app.name, id, created)
+}
+
+/// Takes the document as the raw request body, the format is read from the `format` query
+/// param or else the content type.
+pub fn upload_document(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let mut upload_query: UploadQuery =
+        serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+
+    if upload_query.options.format.is_none() {
+        let content_type = req
+            .headers()
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        upload_query.options.format = Some(
+            match content_type.split(';').next().unwrap_or_default().trim() {
+                "text/markdown" | "text/x-markdown" => DocumentFormat::Markdown,
+                "application/jsonl" | "application/x-ndjson" | "application/x-jsonlines" => {
+                    DocumentFormat::Jsonl
+                }
+                _ => DocumentFormat::Text,
+            },
+        );
+    }
+
+    let content = match req.body() {
+        Some(b) => std::str::from_utf8(b)
+            .map_err(|_| ApiError::BadRequest("Document must be UTF-8 text".to_string()))?
+            .to_string(),
+        None => Default::default(),
+    };
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let (id, created) = ingest(
+        &db,
+        &app.name,
+        &upload_query.title,
+        &content,
+        upload_query.options,
+    )?;
+
+    ingested_response(&db, &app.name, id, created)
+}
+
+pub fn list_document_chunks(_req: Request, params: Params) -> Result<Response> {
+    let (name, id) = route_params(&params, "id", "Document id")?;
+
+    let db = Db::new()?;
+    let document = db.get_document(name, id)?;
+    let chunks = db.list_document_chunks(document.id)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&chunks)?,
+    ))
+}
+
+pub fn list_documents(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let list_query: ListQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+
+    let offset = list_query.offset.unwrap_or(0).max(0);
+    let limit = list_query.limit.unwrap_or(10).clamp(1, 100);
+
+    let db = Db::new()?;
+    let documents = db.list_documents(name, offset, limit)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&documents)?,
+    ))
+}
+
+pub fn get_document(_req: Request, params: Params) -> Result<Response> {
+    let (name, id) = route_params(&params, "id", "Document id")?;
+
+    let db = Db::new()?;
+    let document = db.get_document(name, id)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&document)?,
+    ))
+}
+
+pub fn delete_document(_req: Request, params: Params) -> Result<Response> {
+    let (name, id) = route_params(&params, "id", "Document id")?;
+
+    let db = Db::new()?;
+    db.delete_document(name, id)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .body(None)
+        .unwrap())
+}
+
+/// A chunk along with its embedding and the title of its document, as used for retrieval.
+pub struct StoredChunk {
+    pub id: i64,
+    pub document_id: i64,
+    pub title: String,
+    pub chunk_index: u32,
+    pub content: String,
+    pub embedding: Vec<f32>,
+}
+
+impl Db {
+    pub fn insert_document(
+        &self,
+        app_name: &str,
+        title: &str,
+        content: &str,
+        format: DocumentFormat,
+        split_options: &SplitOptions,
+    ) -> Result<i64> {
+        let split_options = serde_json::to_string(split_options)?;
+        let result = self.conn.execute(
+            "INSERT INTO documents (app_name, title, content, format, split_options)
+      VALUES (?, ?, ?, ?, ?)
+      RETURNING id",
+            &[
+                ValueParam::Text(app_name),
+                ValueParam::Text(title),
+                ValueParam::Text(content),
+                ValueParam::Text(format.as_str()),
+                ValueParam::Text(&split_options),
+            ],
+        )?;
+
+        result
+            .rows
+            .first()
+            .and_then(|r| r.get::<i64>(0))
+            .context("insert did not return the document id")
+    }
+
+    pub fn replace_document(
+        &self,
+        id: i64,
+        content: &str,
+        format: DocumentFormat,
+        split_options: &SplitOptions,
+    ) -> Result<()> {
+        let split_options = serde_json::to_string(split_options)?;
+        self.conn.execute(
+            "UPDATE documents SET content = ?, format = ?, split_options = ? WHERE id = ?",
+            &[
+                ValueParam::Text(content),
+                ValueParam::Text(format.as_str()),
+                ValueParam::Text(&split_options),
+                ValueParam::Integer(id),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn find_document_id(&self, app_name: &str, title: &str) -> Result<Option<i64>> {
+        let result = self.conn.execute(
+            "SELECT id FROM documents WHERE app_name = ? AND title = ? ORDER BY id DESC LIMIT 1",
+            &[ValueParam::Text(app_name), ValueParam::Text(title)],
+        )?;
+        Ok(result.rows.first().and_then(|r| r.get::<i64>(0)))
+    }
+
+    pub fn insert_chunk(
+        &self,
+        document_id: i64,
+        app_name: &str,
+        chunk_index: i64,
+        chunk: &TextChunk,
+        embedding_model: &str,
+        embedding: &[f32],
+    ) -> Result<()> {
+        let embedding = encode_embedding(embedding);
+        let metadata = serde_json::to_string(&chunk.metadata)?;
+        self.conn.execute(
+            "INSERT INTO document_chunks (
+          document_id,
+          app_name,
+          chunk_index,
+          content,
+          start_offset,
+          end_offset,
+          metadata,
+          embedding_model,
+          embedding
+        )
+      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+            &[
+                ValueParam::Integer(document_id),
+                ValueParam::Text(app_name),
+                ValueParam::Integer(chunk_index),
+                ValueParam::Text(&chunk.content),
+                ValueParam::Integer(chunk.start_offset as i64),
+                ValueParam::Integer(chunk.end_offset as i64),
+                ValueParam::Text(&metadata),
+                ValueParam::Text(embedding_model),
+                ValueParam::Blob(&embedding),
+            ],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_chunks(&self, document_id: i64) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM document_chunks WHERE document_id = ?",
+            &[ValueParam::Integer(document_id)],
+        )?;
+        Ok(())
+    }
+
+    pub fn list_document_chunks(&self, document_id: i64) -> Result<Vec<DocumentChunk>> {
+        let result = self.conn.execute(
+            "SELECT chunk_index, content, start_offset, end_offset, metadata
+      FROM document_chunks WHERE document_id = ? ORDER BY chunk_index ASC",
+            &[ValueParam::Integer(document_id)],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        result
+            .rows
+            .iter()
+            .map(|r| {
+                let metadata = r.get::<&str>(col_map["metadata"]).unwrap_or("{}");
+
+                Ok(DocumentChunk {
+                    chunk_index: r.get::<u32>(col_map["chunk_index"]).unwrap_or_default(),
+                    content: r
+                        .get::<&str>(col_map["content"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    start_offset: r.get::<i64>(col_map["start_offset"]).unwrap_or_default(),
+                    end_offset: r.get::<i64>(col_map["end_offset"]).unwrap_or_default(),
+                    metadata: serde_json::from_str(metadata)?,
+                })
+            })
+            .collect()
+    }
+
+    pub fn list_documents(&self, app_name: &str, offset: i64, limit: i64) -> Result<Vec<Document>> {
+        let result = self.conn.execute(
+            "SELECT d.id, d.created_at, d.app_name, d.title, d.format, d.split_options,
+        (SELECT COUNT(*) FROM document_chunks c WHERE c.document_id = d.id) AS chunk_count
+      FROM documents d WHERE d.app_name = ? ORDER BY d.id DESC LIMIT ? OFFSET ?",
+            &[
+                ValueParam::Text(app_name),
+                ValueParam::Integer(limit),
+                ValueParam::Integer(offset),
+            ],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        result
+            .rows
+            .iter()
+            .map(|r| {
+                let split_options = r.get::<&str>(col_map["split_options"]).unwrap_or_default();
+
+                Ok(Document {
+                    id: r.get::<i64>(col_map["id"]).unwrap_or_default(),
+                    created_at: r
+                        .get::<&str>(col_map["created_at"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    app_name: r
+                        .get::<&str>(col_map["app_name"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    title: r
+                        .get::<&str>(col_map["title"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    format: r
+                        .get::<&str>(col_map["format"])
+                        .and_then(DocumentFormat::parse)
+                        .unwrap_or(DocumentFormat::Text),
+                    split_options: serde_json::from_str(split_options)?,
+                    content: String::new(),
+                    chunk_count: r.get::<i64>(col_map["chunk_count"]).unwrap_or_default(),
+                })
+            })
+            .collect()
+    }
+
+    pub fn get_document(&self, app_name: &str, id: i64) -> Result<Document> {
+        let result = self.conn.execute(
+            "SELECT d.*,
+        (SELECT COUNT(*) FROM document_chunks c WHERE c.document_id = d.id) AS chunk_count
+      FROM documents d WHERE d.app_name = ? AND d.id = ?",
+            &[ValueParam::Text(app_name), ValueParam::Integer(id)],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+        let row = result
+            .rows
+            .first()
+            .ok_or_else(|| ApiError::NotFound(format!("Document {} not found", id)))?;
+
+        Ok(Document {
+            id,
+            created_at: row
+                .get::<&str>(col_map["created_at"])
+                .unwrap_or_default()
+                .to_string(),
+            app_name: app_name.to_string(),
+            title: row
+                .get::<&str>(col_map["title"])
+                .unwrap_or_default()
+                .to_string(),
+            format: row
+                .get::<&str>(col_map["format"])
+                .and_then(DocumentFormat::parse)
+                .unwrap_or(DocumentFormat::Text),
+            split_options: serde_json::from_str(
+                row.get::<&str>(col_map["split_options"])
+                    .unwrap_or_default(),
+            )?,
+            content: row
+                .get::<&str>(col_map["content"])
+                .unwrap_or_default()
+                .to_string(),
+            chunk_count: row.get::<i64>(col_map["chunk_count"]).unwrap_or_default(),
+        })
+    }
+
+    pub fn delete_document(&self, app_name: &str, id: i64) -> Result<()> {
+        let result = self.conn.execute(
+            "DELETE FROM documents WHERE app_name = ? AND id = ? RETURNING id",
+            &[ValueParam::Text(app_name), ValueParam::Integer(id)],
+        )?;
+        if result.rows.is_empty() {
+            return Err(ApiError::NotFound(format!("Document {} not found", id)).into());
+        }
+        // the explicit delete is required, Db::new doesn't enable foreign keys so the schema's
+        // ON DELETE CASCADE never runs
+        self.conn.execute(
+            "DELETE FROM document_chunks WHERE document_id = ?",
+            &[ValueParam::Integer(id)],
+        )?;
+        Ok(())
+    }
+
+    pub fn delete_documents(&self, app_name: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM document_chunks WHERE app_name = ?",
+            &[ValueParam::Text(app_name)],
+        )?;
+        self.conn.execute(
+            "DELETE FROM documents WHERE app_name = ?",
+            &[ValueParam::Text(app_name)],
+        )?;
+        Ok(())
+    }
+
+    /// Every chunk of the app embedded with `embedding_model`, similarity is computed in memory
+    /// since SQLite has no vector index.
+    pub fn list_chunks(&self, app_name: &str, embedding_model: &str) -> Result<Vec<StoredChunk>> {
+        let result = self.conn.execute(
+            "SELECT c.id, c.document_id, d.title, c.chunk_index, c.content, c.embedding
+      FROM document_chunks c JOIN documents d ON d.id = c.document_id
+      WHERE c.app_name = ? AND c.embedding_model = ?",
+            &[
+                ValueParam::Text(app_name),
+                ValueParam::Text(embedding_model),
+            ],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        Ok(result
+            .rows
+            .iter()
+            .map(|r| StoredChunk {
+                id: r.get::<i64>(col_map["id"]).unwrap_or_default(),
+                document_id: r.get::<i64>(col_map["document_id"]).unwrap_or_default(),
+                title: r
+                    .get::<&str>(col_map["title"])
+                    .unwrap_or_default()
+                    .to_string(),
+                chunk_index: r.get::<u32>(col_map["chunk_index"]).unwrap_or_default(),
+                content: r
+                    .get::<&str>(col_map["content"])
+                    .unwrap_or_default()
+                    .to_string(),
+                embedding: decode_embedding(
+                    r.get::<&[u8]>(col_map["embedding"]).unwrap_or_default(),
+                ),
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn result(keyword_rank: Option<usize>, similarity: Option<f32>) -> SearchResult {
+        SearchResult {
+            document_id: 1,
+            title: "Errors".to_string(),
+            chunk_index: 0,
+            content: "E1234 means the model is not allowed".to_string(),
+            score: 0.0,
+            keyword_rank,
+            vector_rank: similarity.map(|_| 1),
+            similarity,
+        }
+    }
+
+    #[test]
+    fn keyword_matches_are_relevant_without_similarity() {
+        assert!(is_relevant(&result(Some(1), None)));
+        assert!(is_relevant(&result(Some(3), Some(0.05))));
+    }
+
+    #[test]
+    fn vector_only_matches_need_the_minimum_similarity() {
+        assert!(is_relevant(&result(None, Some(MIN_RETRIEVAL_SIMILARITY))));
+        assert!(is_relevant(&result(None, Some(0.8))));
+        assert!(!is_relevant(&result(None, Some(0.1))));
+        assert!(!is_relevant(&result(None, None)));
+    }
+}
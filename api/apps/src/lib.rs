@@ -1,10 +1,24 @@
 use anyhow::{Context, Result};
 use serde::Deserialize;
+use shared::{error_response, parse_json, validation::Validator, ApiError, GenerationMessage};
 use spin_sdk::{
     http::{Params, Request, Response},
     http_component, http_router,
 };
 
+mod batch;
+mod compare;
+mod completions;
+mod conversations;
+mod evals;
+mod jobs;
+mod knowledge;
+mod migrations;
+mod revisions;
+mod search;
+mod splitting;
+mod sweep;
+
 /// A simple Spin HTTP component.
 #[http_component]
 fn handle_app_api(req: Request) -> Result<Response> {
@@ -16,24 +30,92 @@ fn handle_app_api(req: Request) -> Result<Response> {
         POST "/api/apps" => api::create_app,
         PUT "/api/apps/:name" => api::update_app,
         DELETE "/api/apps/:name" => api::delete_app,
-        POST "/api/apps/:name/completions" => |_req, _params| {
-          Ok(http::Response::builder()
-          .status(http::StatusCode::NOT_IMPLEMENTED)
-          .body(Some("Not implemented".into()))
-          .unwrap())
-        },
+        GET "/api/apps/:name/completions" => completions::list_completions,
+        POST "/api/apps/:name/completions" => completions::create_completion,
+        POST "/api/apps/:name/preview" => completions::preview_completion,
+        POST "/api/apps/:name/batch" => batch::run_batch,
+        GET "/api/apps/:name/conversations" => conversations::list_conversations,
+        POST "/api/apps/:name/conversations" => conversations::create_conversation,
+        GET "/api/apps/:name/conversations/:id" => conversations::get_conversation,
+        POST "/api/apps/:name/conversations/:id/messages" => conversations::create_message,
+        GET "/api/apps/:name/documents" => knowledge::list_documents,
+        POST "/api/apps/:name/documents" => knowledge::create_document,
+        POST "/api/apps/:name/documents/upload" => knowledge::upload_document,
+        GET "/api/apps/:name/documents/:id" => knowledge::get_document,
+        DELETE "/api/apps/:name/documents/:id" => knowledge::delete_document,
+        GET "/api/apps/:name/documents/:id/chunks" => knowledge::list_document_chunks,
+        GET "/api/apps/:name/knowledge/search" => search::search_knowledge,
+        GET "/api/apps/:name/evals" => evals::list_eval_cases,
+        POST "/api/apps/:name/evals" => evals::create_eval_case,
+        POST "/api/apps/:name/evals/run" => evals::run_evals,
+        GET "/api/apps/:name/evals/:id" => evals::get_eval_case,
+        PUT "/api/apps/:name/evals/:id" => evals::update_eval_case,
+        DELETE "/api/apps/:name/evals/:id" => evals::delete_eval_case,
+        POST "/api/apps/:name/sweep" => sweep::run_sweep,
+        GET "/api/apps/:name/diff" => revisions::diff_revisions,
+        GET "/api/apps/:name/revisions" => revisions::list_revisions,
+        GET "/api/apps/:name/revisions/:revision" => revisions::get_revision,
+        POST "/api/apps/:name/revisions/:revision/restore" => revisions::restore_revision,
+        POST "/api/compare" => compare::compare,
+        POST "/api/jobs" => jobs::create_job,
+        POST "/api/jobs/work" => jobs::work,
+        GET "/api/jobs/:id" => jobs::get_job,
         _ "/*" => |_req, _params| {
-          Ok(http::Response::builder()
-          .status(http::StatusCode::NOT_FOUND)
-          .body(Some("Not found".into()))
-          .unwrap())
+          Ok(ApiError::NotFound("Not found".to_string()).to_response())
         }
     };
-    router.handle(req)
+    router.handle(req).or_else(|e| Ok(error_response(&e)))
+}
+
+/// The app name and the integer `id_param` of routes such as `/api/apps/:name/evals/:id`, `label`
+/// names the id in the error when it doesn't parse.
+fn route_params<'a>(params: &'a Params, id_param: &str, label: &str) -> Result<(&'a str, i64)> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    Ok((name, route_id(params, id_param, label)?))
+}
+
+fn route_id(params: &Params, id_param: &str, label: &str) -> Result<i64> {
+    let id = params
+        .get(id_param)
+        .with_context(|| format!("url route missing {} parameter.", id_param))?
+        .parse::<i64>()
+        .map_err(|_| ApiError::BadRequest(format!("{} must be an integer", label)))?;
+    Ok(id)
+}
+
+fn json_response(status: http::StatusCode, body: Vec<u8>) -> Response {
+    http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(body.into()))
+        .unwrap()
+}
+
+/// Checks the roles of messages sent to an app, the system prompt is always added from the app's
+/// configuration so only user and assistant turns are accepted.
+fn validate_messages(v: &mut Validator, messages: &[GenerationMessage]) {
+    for (i, message) in messages.iter().enumerate() {
+        v.check(
+            &format!("messages[{}].role", i),
+            message.role == "user" || message.role == "assistant",
+            "must be user or assistant, the system prompt comes from the app",
+        );
+    }
 }
 
 mod model {
     use serde::{Deserialize, Serialize};
+    use shared::{
+        allowed_models,
+        validation::{validate_params, Validator},
+        ApiError, GenerationModelParams,
+    };
+
+    const MAX_NAME_LENGTH: usize = 64;
+    const MAX_DESCRIPTION_LENGTH: usize = 1000;
+    const MAX_SYSTEM_PROMPT_LENGTH: usize = 8000;
 
     #[derive(Debug, Serialize, Deserialize)]
     pub struct Model {
@@ -56,6 +138,9 @@ mod model {
         #[serde(default)]
         pub description: String,
         pub system_prompt: String,
+        /// Optional Jinja chat template that overrides the model's prompt format.
+        #[serde(default)]
+        pub chat_template: Option<String>,
         pub model: Model,
     }
 
@@ -81,6 +166,7 @@ mod model {
                 name: self.name.clone(),
                 description: self.description.clone(),
                 system_prompt: self.system_prompt.clone(),
+                chat_template: self.chat_template.clone(),
                 model: self.model.clone(),
             }
         }
@@ -101,6 +187,124 @@ mod model {
         }
     }
 
+    impl Model {
+        /// Defaults for a model that isn't configured by an app, the same values the sdk uses.
+        pub fn with_defaults(name: &str) -> Self {
+            let defaults = spin_sdk::llm::InferencingParams::default();
+            Self {
+                name: name.to_string(),
+                max_tokens: defaults.max_tokens,
+                temperature: defaults.temperature as f64,
+                repeat_penalty: defaults.repeat_penalty as f64,
+                repeat_penalty_last_n_tokens: defaults.repeat_penalty_last_n_token_count,
+                top_k: defaults.top_k,
+                top_p: defaults.top_p as f64,
+            }
+        }
+
+        /// Overrides the params that are set, the rest keep their current values.
+        pub fn with_params(mut self, params: &GenerationModelParams) -> Self {
+            if let Some(max_tokens) = params.max_tokens {
+                self.max_tokens = max_tokens;
+            }
+            if let Some(temperature) = params.temperature {
+                self.temperature = temperature as f64;
+            }
+            if let Some(repeat_penalty) = params.repeat_penalty {
+                self.repeat_penalty = repeat_penalty as f64;
+            }
+            if let Some(last_n) = params.repeat_penalty_last_n_token_count {
+                self.repeat_penalty_last_n_tokens = last_n;
+            }
+            if let Some(top_k) = params.top_k {
+                self.top_k = top_k;
+            }
+            if let Some(top_p) = params.top_p {
+                self.top_p = top_p as f64;
+            }
+            self
+        }
+    }
+
+    impl From<&Model> for GenerationModelParams {
+        fn from(model: &Model) -> Self {
+            Self {
+                max_tokens: Some(model.max_tokens),
+                repeat_penalty: Some(model.repeat_penalty as f32),
+                repeat_penalty_last_n_token_count: Some(model.repeat_penalty_last_n_tokens),
+                temperature: Some(model.temperature as f32),
+                top_k: Some(model.top_k),
+                top_p: Some(model.top_p as f32),
+            }
+        }
+    }
+
+    /// Names are used in urls so they are restricted to lowercase slugs like `basic-chat`.
+    fn is_slug(name: &str) -> bool {
+        !name.starts_with('-')
+            && !name.ends_with('-')
+            && !name.contains("--")
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-')
+    }
+
+    impl App {
+        pub fn validate(&self) -> Result<(), ApiError> {
+            let mut v = Validator::new();
+            v.check("name", !self.name.is_empty(), "is required")
+                .check(
+                    "name",
+                    self.name.len() <= MAX_NAME_LENGTH,
+                    &format!("must be at most {} characters", MAX_NAME_LENGTH),
+                )
+                .check(
+                    "name",
+                    is_slug(&self.name),
+                    "must only contain lowercase letters, numbers and single hyphens",
+                )
+                .check(
+                    "description",
+                    self.description.chars().count() <= MAX_DESCRIPTION_LENGTH,
+                    &format!("must be at most {} characters", MAX_DESCRIPTION_LENGTH),
+                )
+                .check(
+                    "system_prompt",
+                    self.system_prompt.chars().count() <= MAX_SYSTEM_PROMPT_LENGTH,
+                    &format!("must be at most {} characters", MAX_SYSTEM_PROMPT_LENGTH),
+                );
+            if let Some(template) = &self.chat_template {
+                if let Err(e) = shared::template_prompt::check_template(template) {
+                    v.check(
+                        "chat_template",
+                        false,
+                        &format!("is not a valid template: {}", e),
+                    );
+                }
+            }
+
+            let available_models = allowed_models();
+            v.check(
+                "model.name",
+                available_models.contains(&self.model.name),
+                &format!("must be one of: {}", available_models.join(", ")),
+            );
+            validate_params(
+                &mut v,
+                &self.model.name,
+                &(&self.model).into(),
+                |f| match f {
+                    "repeat_penalty_last_n_token_count" => {
+                        "model.repeat_penalty_last_n_tokens".to_string()
+                    }
+                    _ => format!("model.{}", f),
+                },
+            );
+
+            v.finish()
+        }
+    }
+
     impl Default for App {
         fn default() -> Self {
             Self {
@@ -109,6 +313,7 @@ mod model {
                 name: Default::default(),
                 description: Default::default(),
                 system_prompt: Default::default(),
+                chat_template: Default::default(),
                 model: Default::default(),
             }
         }
@@ -119,14 +324,14 @@ mod api {
     use super::*;
 
     #[derive(Debug, Deserialize)]
-    struct ListQuery {
-        offset: Option<i64>,
-        limit: Option<i64>,
+    pub struct ListQuery {
+        pub offset: Option<i64>,
+        pub limit: Option<i64>,
     }
 
     pub fn list_apps(req: Request, _params: Params) -> Result<Response> {
-        let list_query: ListQuery = serde_qs::from_str(req.uri().to_string().as_str())
-            .context("failed to parse the request url")?;
+        let list_query: ListQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+            .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
 
         //TODO: clamp the values to something reasonable
         let offset = list_query.offset.unwrap_or(0);
@@ -166,15 +371,18 @@ mod api {
             Some(b) => b.to_vec(),
             None => Default::default(),
         };
-        let new_app = serde_json::from_slice::<model::App>(&req_body)?;
-
-        //TODO: validate the data
-
-        //TODO: make sure the name is not taken
+        let new_app = parse_json::<model::App>(&req_body)?;
+        new_app.validate()?;
 
+        // the name check, the insert and the first revision happen in one write transaction so
+        // concurrent requests for the same name can't both pass the check
         let db = data::Db::new()?;
-        db.insert(&new_app)?;
-        let app = db.get(&new_app.name)?;
+        let app = db.transaction(|| {
+            db.insert(&new_app)?;
+            let app = db.get(&new_app.name)?;
+            db.insert_revision(&app, None)?;
+            Ok(app)
+        })?;
 
         let resp_body = serde_json::to_vec(&app)?;
 
@@ -186,18 +394,27 @@ mod api {
             .unwrap())
     }
 
-    pub fn update_app(req: Request, _params: Params) -> Result<Response> {
+    pub fn update_app(req: Request, params: Params) -> Result<Response> {
+        let name = params
+            .get("name")
+            .context("url route missing name parameter.")?;
         let req_body = match req.body() {
             Some(b) => b.to_vec(),
             None => Default::default(),
         };
-        let new_app = serde_json::from_slice::<model::App>(&req_body)?;
-
-        //TODO: validate the data
+        let new_app = parse_json::<model::App>(&req_body)?;
+        if new_app.name != name {
+            return Err(ApiError::BadRequest("App name cannot be changed".to_string()).into());
+        }
+        new_app.validate()?;
 
         let db = data::Db::new()?;
-        db.update(&new_app)?;
-        let app = db.get(&new_app.name)?;
+        let app = db.transaction(|| {
+            db.update(&new_app)?;
+            let app = db.get(&new_app.name)?;
+            db.insert_revision(&app, None)?;
+            Ok(app)
+        })?;
 
         let resp_body = serde_json::to_vec(&app)?;
 
@@ -213,8 +430,18 @@ mod api {
             .get("name")
             .context("url route missing name parameter.")?;
 
+        // history is keyed by name so it goes with the app, otherwise a new app with the same
+        // name would inherit it
         let db = data::Db::new()?;
-        db.delete(&name)?;
+        db.transaction(|| {
+            db.delete(name)?;
+            db.delete_revisions(name)?;
+            db.delete_documents(name)?;
+            db.delete_eval_cases(name)?;
+            db.delete_completions(name)?;
+            db.delete_conversations(name)?;
+            db.delete_jobs(name)
+        })?;
 
         Ok(http::Response::builder()
             .status(http::StatusCode::OK)
@@ -232,15 +459,32 @@ mod data {
     use super::*;
 
     pub struct Db {
-        conn: Connection,
+        pub conn: Connection,
     }
 
     impl Db {
         pub fn new() -> Result<Self> {
             let conn = Connection::open_default()?;
+            crate::migrations::migrate(&conn)?;
             Ok(Self { conn })
         }
 
+        /// Runs `f` in a write transaction and rolls back when it fails. IMMEDIATE takes the
+        /// write lock up front so concurrent requests queue behind each other.
+        pub fn transaction<T>(&self, f: impl FnOnce() -> Result<T>) -> Result<T> {
+            self.conn.execute("BEGIN IMMEDIATE", &[])?;
+            match f() {
+                Ok(value) => {
+                    self.conn.execute("COMMIT", &[])?;
+                    Ok(value)
+                }
+                Err(e) => {
+                    self.conn.execute("ROLLBACK", &[]).ok();
+                    Err(e)
+                }
+            }
+        }
+
         pub fn list(&self, offset: i64, limit: i64) -> Result<Vec<App>> {
             let apps = self.select(
                 "SELECT * FROM apps ORDER BY name ASC LIMIT ? OFFSET ?",
@@ -257,19 +501,26 @@ mod data {
             )?;
 
             if apps.len() == 0 {
-                return Err(anyhow::anyhow!("App not found"));
+                return Err(ApiError::NotFound(format!("App '{}' not found", name)).into());
             }
 
             // TODO: not sure how to handle this other than cloning?
             Ok(apps[0].clone())
         }
 
+        /// Fails with a conflict when the name is taken, run it in a transaction so the check
+        /// and the insert can't interleave with another request's.
         pub fn insert(&self, app: &App) -> Result<()> {
+            if self.exists(&app.name)? {
+                return Err(Self::name_taken(&app.name).into());
+            }
+
             self.conn.execute(
                 "INSERT INTO apps (
           name,
           description,
           system_prompt,
+          chat_template,
           model_name,
           model_max_tokens,
           model_temperature,
@@ -278,11 +529,12 @@ mod data {
           model_top_k,
           model_top_p
         )
-      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
+      VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?, ?, ?)",
                 &[
                     ValueParam::Text(&app.name),
                     ValueParam::Text(&app.description),
                     ValueParam::Text(&app.system_prompt),
+                    Self::optional_text(&app.chat_template),
                     ValueParam::Text(&app.model.name),
                     ValueParam::Integer(app.model.max_tokens as i64),
                     ValueParam::Real(app.model.temperature),
@@ -292,15 +544,15 @@ mod data {
                     ValueParam::Real(app.model.top_p),
                 ],
             )?;
-
             Ok(())
         }
 
         pub fn update(&self, app: &App) -> Result<()> {
-            self.conn.execute(
+            let result = self.conn.execute(
                 "UPDATE apps SET
         description = ?,
         system_prompt = ?,
+        chat_template = ?,
         model_name = ?,
         model_max_tokens = ?,
         model_temperature = ?,
@@ -308,10 +560,12 @@ mod data {
         model_repeat_penalty_last_n_tokens = ?,
         model_top_k = ?,
         model_top_p = ?
-        WHERE name = ?",
+        WHERE name = ?
+        RETURNING name",
                 &[
                     ValueParam::Text(&app.description),
                     ValueParam::Text(&app.system_prompt),
+                    Self::optional_text(&app.chat_template),
                     ValueParam::Text(&app.model.name),
                     ValueParam::Integer(app.model.max_tokens as i64),
                     ValueParam::Real(app.model.temperature),
@@ -322,9 +576,28 @@ mod data {
                     ValueParam::Text(&app.name),
                 ],
             )?;
+
+            if result.rows.is_empty() {
+                return Err(ApiError::NotFound(format!("App '{}' not found", app.name)).into());
+            }
             Ok(())
         }
 
+        pub fn exists(&self, name: &str) -> Result<bool> {
+            let result = self.conn.execute(
+                "SELECT 1 FROM apps WHERE name = ?",
+                &[ValueParam::Text(name)],
+            )?;
+            Ok(!result.rows.is_empty())
+        }
+
+        fn name_taken(name: &str) -> ApiError {
+            ApiError::Conflict {
+                message: format!("App '{}' already exists", name),
+                location: Some(format!("/api/apps/{}", name)),
+            }
+        }
+
         pub fn delete(&self, name: &str) -> Result<()> {
             self.conn.execute(
                 "DELETE FROM apps WHERE name = ?",
@@ -362,6 +635,9 @@ mod data {
                         .get::<&str>(col_map["system_prompt"])
                         .unwrap_or_default()
                         .to_string();
+                    let chat_template = r
+                        .get::<&str>(col_map["chat_template"])
+                        .map(|t| t.to_string());
                     let model_name = r
                         .get::<&str>(col_map["model_name"])
                         .unwrap_or_default()
@@ -406,6 +682,7 @@ mod data {
                         name,
                         description,
                         system_prompt: system_prompt,
+                        chat_template,
                         model: Model {
                             name: model_name,
                             max_tokens: model_max_tokens,
@@ -420,7 +697,14 @@ mod data {
                 .collect())
         }
 
-        fn get_column_lookup<'a>(columns: &'a Vec<String>) -> HashMap<&'a str, usize> {
+        fn optional_text(value: &Option<String>) -> ValueParam<'_> {
+            match value {
+                Some(v) => ValueParam::Text(v),
+                None => ValueParam::Null,
+            }
+        }
+
+        pub fn get_column_lookup<'a>(columns: &'a Vec<String>) -> HashMap<&'a str, usize> {
             columns
                 .iter()
                 .enumerate()
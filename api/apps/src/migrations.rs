@@ -0,0 +1,325 @@
+//! Embedded schema migrations, applied in order the first time a connection is opened.
+//!
+//! Each migration runs in its own transaction and is recorded in `schema_migrations`, so new
+//! columns and tables can be added without wiping existing apps. Never edit a migration that
+//! has shipped, add a new one instead.
+
+use anyhow::{Context, Result};
+use spin_sdk::sqlite::{Connection, ValueParam};
+
+pub struct Migration {
+    pub version: i64,
+    pub name: &'static str,
+    pub statements: &'static [&'static str],
+}
+
+pub const MIGRATIONS: &[Migration] = &[
+    Migration {
+        version: 1,
+        name: "create_apps",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS apps (
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    name TEXT UNIQUE NOT NULL,
+    description TEXT,
+    system_prompt TEXT,
+    model_name TEXT NOT NULL,
+    model_max_tokens INTEGER,
+    model_temperature REAL,
+    model_repeat_penalty REAL,
+    model_repeat_penalty_last_n_tokens INTEGER,
+    model_top_k INTEGER,
+    model_top_p REAL
+)"#,
+            r#"CREATE TRIGGER IF NOT EXISTS apps_update_updated_at_trigger
+AFTER UPDATE ON apps FOR EACH ROW
+WHEN OLD.updated_at = NEW.updated_at OR OLD.updated_at IS NULL
+BEGIN
+   UPDATE apps SET updated_at = CURRENT_TIMESTAMP WHERE name = NEW.name;
+END"#,
+        ],
+    },
+    Migration {
+        version: 2,
+        name: "add_apps_chat_template",
+        statements: &["ALTER TABLE apps ADD COLUMN chat_template TEXT"],
+    },
+    Migration {
+        version: 3,
+        name: "create_completions",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS completions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    app_name TEXT NOT NULL,
+    app_snapshot TEXT NOT NULL,
+    messages TEXT NOT NULL,
+    prompt TEXT NOT NULL,
+    output TEXT NOT NULL,
+    prompt_token_count INTEGER NOT NULL,
+    generated_token_count INTEGER NOT NULL,
+    duration_ms INTEGER NOT NULL
+)"#,
+            "CREATE INDEX IF NOT EXISTS completions_app_name_idx ON completions (app_name, id)",
+        ],
+    },
+    Migration {
+        version: 4,
+        name: "create_conversations",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS conversations (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    app_name TEXT NOT NULL,
+    title TEXT NOT NULL DEFAULT ''
+)"#,
+            "CREATE INDEX IF NOT EXISTS conversations_app_name_idx ON conversations (app_name, updated_at)",
+            r#"CREATE TABLE IF NOT EXISTS conversation_messages (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    conversation_id INTEGER NOT NULL REFERENCES conversations (id) ON DELETE CASCADE,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL
+)"#,
+            "CREATE INDEX IF NOT EXISTS conversation_messages_conversation_id_idx ON conversation_messages (conversation_id, id)",
+        ],
+    },
+    Migration {
+        version: 5,
+        name: "add_completions_dropped_messages",
+        statements: &["ALTER TABLE completions ADD COLUMN dropped_messages TEXT NOT NULL DEFAULT '[]'"],
+    },
+    Migration {
+        version: 6,
+        name: "create_app_revisions",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS app_revisions (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    app_name TEXT NOT NULL,
+    revision INTEGER NOT NULL,
+    app_snapshot TEXT NOT NULL,
+    restored_from INTEGER
+)"#,
+            "CREATE UNIQUE INDEX IF NOT EXISTS app_revisions_app_name_revision_idx ON app_revisions (app_name, revision)",
+            // existing apps start their history from their current configuration
+            r#"INSERT INTO app_revisions (app_name, revision, app_snapshot)
+SELECT name, 1, json_object(
+    'created_at', created_at,
+    'updated_at', updated_at,
+    'name', name,
+    'description', COALESCE(description, ''),
+    'system_prompt', COALESCE(system_prompt, ''),
+    'chat_template', chat_template,
+    'model', json_object(
+        'name', model_name,
+        'max_tokens', COALESCE(model_max_tokens, 0),
+        'temperature', COALESCE(model_temperature, 0.0),
+        'repeat_penalty', COALESCE(model_repeat_penalty, 0.0),
+        'repeat_penalty_last_n_tokens', COALESCE(model_repeat_penalty_last_n_tokens, 0),
+        'top_k', COALESCE(model_top_k, 0),
+        'top_p', COALESCE(model_top_p, 0.0)
+    )
+)
+FROM apps"#,
+        ],
+    },
+    Migration {
+        version: 7,
+        name: "create_documents",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS documents (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    app_name TEXT NOT NULL,
+    title TEXT NOT NULL,
+    content TEXT NOT NULL
+)"#,
+            "CREATE INDEX IF NOT EXISTS documents_app_name_idx ON documents (app_name, id)",
+            r#"CREATE TABLE IF NOT EXISTS document_chunks (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    document_id INTEGER NOT NULL REFERENCES documents (id) ON DELETE CASCADE,
+    app_name TEXT NOT NULL,
+    chunk_index INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    embedding_model TEXT NOT NULL,
+    embedding BLOB NOT NULL
+)"#,
+            "CREATE INDEX IF NOT EXISTS document_chunks_app_name_idx ON document_chunks (app_name, embedding_model)",
+            "CREATE INDEX IF NOT EXISTS document_chunks_document_id_idx ON document_chunks (document_id, chunk_index)",
+        ],
+    },
+    Migration {
+        version: 8,
+        name: "add_completions_sources",
+        statements: &["ALTER TABLE completions ADD COLUMN sources TEXT NOT NULL DEFAULT '[]'"],
+    },
+    Migration {
+        version: 9,
+        name: "add_document_ingestion_columns",
+        statements: &[
+            "ALTER TABLE documents ADD COLUMN format TEXT NOT NULL DEFAULT 'text'",
+            // documents added before splitting was configurable were packed into ~1000 character paragraphs
+            r#"ALTER TABLE documents ADD COLUMN split_options TEXT NOT NULL DEFAULT '{"strategy":"paragraphs","chunk_size":250,"overlap":0}'"#,
+            "ALTER TABLE document_chunks ADD COLUMN start_offset INTEGER",
+            "ALTER TABLE document_chunks ADD COLUMN end_offset INTEGER",
+            "ALTER TABLE document_chunks ADD COLUMN metadata TEXT NOT NULL DEFAULT '{}'",
+            "CREATE INDEX IF NOT EXISTS documents_app_name_title_idx ON documents (app_name, title)",
+        ],
+    },
+    Migration {
+        version: 10,
+        name: "create_document_chunks_fts",
+        statements: &[
+            // `_` is part of a token so snake_case identifiers are matched whole
+            r#"CREATE VIRTUAL TABLE IF NOT EXISTS document_chunks_fts USING fts5(
+    content,
+    content = 'document_chunks',
+    content_rowid = 'id',
+    tokenize = "unicode61 tokenchars '_'"
+)"#,
+            r#"CREATE TRIGGER IF NOT EXISTS document_chunks_fts_insert_trigger
+AFTER INSERT ON document_chunks
+BEGIN
+   INSERT INTO document_chunks_fts (rowid, content) VALUES (NEW.id, NEW.content);
+END"#,
+            r#"CREATE TRIGGER IF NOT EXISTS document_chunks_fts_delete_trigger
+AFTER DELETE ON document_chunks
+BEGIN
+   INSERT INTO document_chunks_fts (document_chunks_fts, rowid, content) VALUES ('delete', OLD.id, OLD.content);
+END"#,
+            r#"CREATE TRIGGER IF NOT EXISTS document_chunks_fts_update_trigger
+AFTER UPDATE OF content ON document_chunks
+BEGIN
+   INSERT INTO document_chunks_fts (document_chunks_fts, rowid, content) VALUES ('delete', OLD.id, OLD.content);
+   INSERT INTO document_chunks_fts (rowid, content) VALUES (NEW.id, NEW.content);
+END"#,
+            "INSERT INTO document_chunks_fts (document_chunks_fts) VALUES ('rebuild')",
+        ],
+    },
+    Migration {
+        version: 11,
+        name: "create_eval_cases",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS eval_cases (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    app_name TEXT NOT NULL,
+    name TEXT NOT NULL,
+    messages TEXT NOT NULL,
+    assertions TEXT NOT NULL
+)"#,
+            "CREATE INDEX IF NOT EXISTS eval_cases_app_name_idx ON eval_cases (app_name, id)",
+        ],
+    },
+    Migration {
+        version: 12,
+        name: "create_jobs",
+        statements: &[
+            r#"CREATE TABLE IF NOT EXISTS jobs (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    kind TEXT NOT NULL,
+    app_name TEXT NOT NULL,
+    app_snapshot TEXT NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending'
+)"#,
+            r#"CREATE TABLE IF NOT EXISTS job_items (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    job_id INTEGER NOT NULL,
+    item_index INTEGER NOT NULL,
+    status TEXT NOT NULL DEFAULT 'pending',
+    input TEXT NOT NULL,
+    result TEXT,
+    error TEXT,
+    claimed_at TIMESTAMP
+)"#,
+            "CREATE INDEX IF NOT EXISTS job_items_job_id_idx ON job_items (job_id, item_index)",
+            "CREATE INDEX IF NOT EXISTS job_items_status_idx ON job_items (status, id)",
+        ],
+    },
+    Migration {
+        version: 13,
+        name: "add_job_items_attempts",
+        statements: &["ALTER TABLE job_items ADD COLUMN attempts INTEGER NOT NULL DEFAULT 0"],
+    },
+];
+
+/// Creates the `schema_migrations` table and applies every migration newer than the recorded
+/// version. Returns the versions that were applied.
+pub fn migrate(conn: &Connection) -> Result<Vec<i64>> {
+    conn.execute(
+        "CREATE TABLE IF NOT EXISTS schema_migrations (
+          version INTEGER PRIMARY KEY,
+          name TEXT NOT NULL,
+          applied_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+        )",
+        &[],
+    )?;
+
+    let current = current_version(conn)?;
+    let mut applied = Vec::new();
+    for migration in MIGRATIONS.iter().filter(|m| m.version > current) {
+        if apply(conn, migration).with_context(|| {
+            format!(
+                "failed to apply migration {}_{}",
+                migration.version, migration.name
+            )
+        })? {
+            applied.push(migration.version);
+        }
+    }
+    Ok(applied)
+}
+
+pub fn current_version(conn: &Connection) -> Result<i64> {
+    let result = conn.execute("SELECT MAX(version) FROM schema_migrations", &[])?;
+    Ok(result
+        .rows
+        .first()
+        .and_then(|r| r.get::<i64>(0))
+        .unwrap_or_default())
+}
+
+/// Runs a single migration in a transaction, returns false when another request got to it first.
+fn apply(conn: &Connection, migration: &Migration) -> Result<bool> {
+    // IMMEDIATE takes the write lock up front so concurrent requests queue behind each other
+    conn.execute("BEGIN IMMEDIATE", &[])?;
+
+    let result = (|| -> Result<bool> {
+        let already_applied = conn.execute(
+            "SELECT 1 FROM schema_migrations WHERE version = ?",
+            &[ValueParam::Integer(migration.version)],
+        )?;
+        if !already_applied.rows.is_empty() {
+            return Ok(false);
+        }
+
+        for statement in migration.statements {
+            conn.execute(statement, &[])?;
+        }
+
+        conn.execute(
+            "INSERT INTO schema_migrations (version, name) VALUES (?, ?)",
+            &[
+                ValueParam::Integer(migration.version),
+                ValueParam::Text(migration.name),
+            ],
+        )?;
+        Ok(true)
+    })();
+
+    match result {
+        Ok(applied) => {
+            conn.execute("COMMIT", &[])?;
+            Ok(applied)
+        }
+        Err(e) => {
+            conn.execute("ROLLBACK", &[]).ok();
+            Err(e)
+        }
+    }
+}
@@ -0,0 +1,321 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use shared::ApiError;
+use similar::TextDiff;
+use spin_sdk::{
+    http::{Params, Request, Response},
+    sqlite::ValueParam,
+};
+
+use crate::{api::ListQuery, data::Db, json_response, model::App, route_params};
+
+/// An immutable snapshot of an app's configuration, a new one is recorded every time the app is
+/// saved. Revisions are numbered per app starting at 1.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Revision {
+    pub revision: i64,
+    pub created_at: String,
+    pub app_name: String,
+    /// The revision this one was restored from, if it was created by a rollback.
+    pub restored_from: Option<i64>,
+    pub app: App,
+}
+
+#[derive(Debug, Deserialize)]
+struct DiffQuery {
+    from: Option<i64>,
+    to: Option<i64>,
+}
+
+/// What changed between two revisions of an app.
+#[derive(Debug, Serialize)]
+pub struct RevisionDiff {
+    pub app_name: String,
+    pub from: i64,
+    pub to: i64,
+    pub system_prompt: TextChange,
+    pub chat_template: TextChange,
+    /// Every scalar field of the app, changed or not, so the two versions can be shown side by side.
+    pub fields: Vec<FieldChange>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TextChange {
+    pub changed: bool,
+    /// Unified diff with three lines of context, empty when nothing changed.
+    pub unified_diff: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct FieldChange {
+    pub field: String,
+    pub from: serde_json::Value,
+    pub to: serde_json::Value,
+    pub changed: bool,
+}
+
+impl TextChange {
+    fn new(old: &str, new: &str, from: i64, to: i64) -> Self {
+        if old == new {
+            return Self {
+                changed: false,
+                unified_diff: String::new(),
+            };
+        }
+        let unified_diff = TextDiff::from_lines(old, new)
+            .unified_diff()
+            .context_radius(3)
+            .header(&format!("revision {}", from), &format!("revision {}", to))
+            .to_string();
+        Self {
+            changed: true,
+            unified_diff,
+        }
+    }
+}
+
+impl FieldChange {
+    fn new(field: &str, from: impl Serialize, to: impl Serialize) -> Self {
+        let from = serde_json::to_value(from).unwrap_or_default();
+        let to = serde_json::to_value(to).unwrap_or_default();
+        Self {
+            field: field.to_string(),
+            changed: from != to,
+            from,
+            to,
+        }
+    }
+}
+
+impl RevisionDiff {
+    pub fn new(from: &Revision, to: &Revision) -> Self {
+        let (old, new) = (&from.app, &to.app);
+        Self {
+            app_name: to.app_name.clone(),
+            from: from.revision,
+            to: to.revision,
+            system_prompt: TextChange::new(
+                &old.system_prompt,
+                &new.system_prompt,
+                from.revision,
+                to.revision,
+            ),
+            chat_template: TextChange::new(
+                old.chat_template.as_deref().unwrap_or_default(),
+                new.chat_template.as_deref().unwrap_or_default(),
+                from.revision,
+                to.revision,
+            ),
+            fields: vec![
+                FieldChange::new("description", &old.description, &new.description),
+                FieldChange::new("model.name", &old.model.name, &new.model.name),
+                FieldChange::new(
+                    "model.max_tokens",
+                    old.model.max_tokens,
+                    new.model.max_tokens,
+                ),
+                FieldChange::new(
+                    "model.temperature",
+                    old.model.temperature,
+                    new.model.temperature,
+                ),
+                FieldChange::new(
+                    "model.repeat_penalty",
+                    old.model.repeat_penalty,
+                    new.model.repeat_penalty,
+                ),
+                FieldChange::new(
+                    "model.repeat_penalty_last_n_tokens",
+                    old.model.repeat_penalty_last_n_tokens,
+                    new.model.repeat_penalty_last_n_tokens,
+                ),
+                FieldChange::new("model.top_k", old.model.top_k, new.model.top_k),
+                FieldChange::new("model.top_p", old.model.top_p, new.model.top_p),
+            ],
+        }
+    }
+}
+
+pub fn list_revisions(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let list_query: ListQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+
+    let offset = list_query.offset.unwrap_or(0).max(0);
+    let limit = list_query.limit.unwrap_or(10).clamp(1, 100);
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let revisions = db.list_revisions(&app.name, offset, limit)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&revisions)?,
+    ))
+}
+
+pub fn get_revision(_req: Request, params: Params) -> Result<Response> {
+    let (name, revision) = route_params(&params, "revision", "Revision")?;
+
+    let db = Db::new()?;
+    let revision = db.get_revision(name, revision)?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&revision)?,
+    ))
+}
+
+/// Compares two revisions, `to` defaults to the latest revision and `from` to the one before it.
+pub fn diff_revisions(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let diff_query: DiffQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let to = match diff_query.to {
+        Some(to) => db.get_revision(&app.name, to)?,
+        None => db
+            .list_revisions(&app.name, 0, 1)?
+            .pop()
+            .ok_or_else(|| ApiError::NotFound(format!("App '{}' has no revisions", app.name)))?,
+    };
+    let from = match diff_query.from {
+        Some(from) => db.get_revision(&app.name, from)?,
+        None if to.revision > 1 => db.get_revision(&app.name, to.revision - 1)?,
+        None => {
+            return Err(ApiError::BadRequest(
+                "Revision 1 has no previous revision, pass `from` explicitly".to_string(),
+            )
+            .into())
+        }
+    };
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&RevisionDiff::new(&from, &to))?,
+    ))
+}
+
+/// Saves the configuration of an earlier revision as the app's current configuration. History is
+/// never rewritten, the restore is recorded as a new revision.
+pub fn restore_revision(_req: Request, params: Params) -> Result<Response> {
+    let (name, revision) = route_params(&params, "revision", "Revision")?;
+
+    let db = Db::new()?;
+    let restored = db.get_revision(name, revision)?;
+
+    // the allowed models may have changed since the revision was saved
+    let mut app = restored.app;
+    app.name = name.to_string();
+    app.validate()?;
+
+    let app = db.transaction(|| {
+        db.update(&app)?;
+        let app = db.get(name)?;
+        db.insert_revision(&app, Some(revision))?;
+        Ok(app)
+    })?;
+
+    Ok(json_response(
+        http::StatusCode::OK,
+        serde_json::to_vec(&app)?,
+    ))
+}
+
+impl Db {
+    /// Records `app` as the next revision, returns the new revision number.
+    pub fn insert_revision(&self, app: &App, restored_from: Option<i64>) -> Result<i64> {
+        let app_snapshot = serde_json::to_string(app)?;
+        let restored_from = match restored_from {
+            Some(r) => ValueParam::Integer(r),
+            None => ValueParam::Null,
+        };
+
+        // numbering in the same statement keeps concurrent saves from sharing a revision
+        let result = self.conn.execute(
+            "INSERT INTO app_revisions (app_name, revision, app_snapshot, restored_from)
+      SELECT ?, COALESCE(MAX(revision), 0) + 1, ?, ? FROM app_revisions WHERE app_name = ?
+      RETURNING revision",
+            &[
+                ValueParam::Text(&app.name),
+                ValueParam::Text(&app_snapshot),
+                restored_from,
+                ValueParam::Text(&app.name),
+            ],
+        )?;
+
+        result
+            .rows
+            .first()
+            .and_then(|r| r.get::<i64>(0))
+            .context("insert did not return the revision number")
+    }
+
+    pub fn list_revisions(&self, app_name: &str, offset: i64, limit: i64) -> Result<Vec<Revision>> {
+        self.select_revisions(
+            "SELECT * FROM app_revisions WHERE app_name = ? ORDER BY revision DESC LIMIT ? OFFSET ?",
+            &[
+                ValueParam::Text(app_name),
+                ValueParam::Integer(limit),
+                ValueParam::Integer(offset),
+            ],
+        )
+    }
+
+    pub fn get_revision(&self, app_name: &str, revision: i64) -> Result<Revision> {
+        self.select_revisions(
+            "SELECT * FROM app_revisions WHERE app_name = ? AND revision = ?",
+            &[ValueParam::Text(app_name), ValueParam::Integer(revision)],
+        )?
+        .pop()
+        .ok_or_else(|| {
+            ApiError::NotFound(format!(
+                "Revision {} of app '{}' not found",
+                revision, app_name
+            ))
+            .into()
+        })
+    }
+
+    pub fn delete_revisions(&self, app_name: &str) -> Result<()> {
+        self.conn.execute(
+            "DELETE FROM app_revisions WHERE app_name = ?",
+            &[ValueParam::Text(app_name)],
+        )?;
+        Ok(())
+    }
+
+    fn select_revisions(&self, query: &str, params: &[ValueParam]) -> Result<Vec<Revision>> {
+        let result = self.conn.execute(query, params)?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        result
+            .rows
+            .iter()
+            .map(|r| {
+                let app_snapshot = r.get::<&str>(col_map["app_snapshot"]).unwrap_or_default();
+
+                Ok(Revision {
+                    revision: r.get::<i64>(col_map["revision"]).unwrap_or_default(),
+                    created_at: r
+                        .get::<&str>(col_map["created_at"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    app_name: r
+                        .get::<&str>(col_map["app_name"])
+                        .unwrap_or_default()
+                        .to_string(),
+                    restored_from: r.get::<i64>(col_map["restored_from"]),
+                    app: serde_json::from_str(app_snapshot)?,
+                })
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use shared::{default_embedding_model, ApiError};
+use spin_sdk::{
+    http::{Params, Request, Response},
+    sqlite::ValueParam,
+};
+
+use crate::{
+    data::Db,
+    knowledge::{cosine_similarity, embed},
+};
+
+/// Damping constant for reciprocal rank fusion, 60 is the value from the original paper.
+const RRF_K: f32 = 60.0;
+/// How many hits each ranker contributes before the rankings are fused.
+const CANDIDATES: usize = 20;
+const DEFAULT_LIMIT: i64 = 5;
+
+#[derive(Debug, Clone, Copy, PartialEq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SearchMode {
+    Hybrid,
+    Keyword,
+    Vector,
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchQuery {
+    q: String,
+    limit: Option<i64>,
+    mode: Option<SearchMode>,
+}
+
+/// A chunk matching a search. `score` is the fused reciprocal rank, the ranks are 1-based
+/// positions in each ranking the chunk appeared in.
+#[derive(Debug, Clone, Serialize)]
+pub struct SearchResult {
+    pub document_id: i64,
+    pub title: String,
+    pub chunk_index: u32,
+    pub content: String,
+    pub score: f32,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub keyword_rank: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub vector_rank: Option<usize>,
+    /// Cosine similarity to the query, when the chunk was found by the vector search.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub similarity: Option<f32>,
+}
+
+/// Turns free text into an FTS5 query that matches any of its terms. Every term is quoted so
+/// identifiers such as `foo.bar()` or `--flag` can't be read as FTS5 syntax.
+fn fts_query(query: &str) -> String {
+    query
+        .split(|c: char| !(c.is_alphanumeric() || c == '_'))
+        .filter(|t| !t.is_empty())
+        .map(|t| format!("\"{}\"", t))
+        .collect::<Vec<_>>()
+        .join(" OR ")
+}
+
+/// Ranks the app's chunks against `query` by BM25 and embedding similarity and merges the two
+/// rankings with reciprocal rank fusion.
+pub fn search(
+    db: &Db,
+    app_name: &str,
+    query: &str,
+    mode: SearchMode,
+    limit: usize,
+) -> Result<Vec<SearchResult>> {
+    let candidates = limit.max(CANDIDATES);
+    let mut results: HashMap<i64, SearchResult> = HashMap::new();
+
+    if mode != SearchMode::Vector {
+        let fts_query = fts_query(query);
+        if !fts_query.is_empty() {
+            for (rank, (id, mut hit)) in db
+                .keyword_search(app_name, &fts_query, candidates)?
+                .into_iter()
+                .enumerate()
+            {
+                hit.keyword_rank = Some(rank + 1);
+                hit.score += 1.0 / (RRF_K + (rank + 1) as f32);
+                results.insert(id, hit);
+            }
+        }
+    }
+
+    if mode != SearchMode::Keyword {
+        let model = default_embedding_model();
+        let chunks = db.list_chunks(app_name, &model)?;
+        // apps without documents skip the embedding call entirely
+        if !chunks.is_empty() {
+            let query_embedding = embed(&model, &[query.to_string()])?
+                .pop()
+                .context("embedding model returned no embedding for the query")?;
+
+            let mut scored: Vec<(f32, _)> = chunks
+                .into_iter()
+                .map(|c| (cosine_similarity(&query_embedding, &c.embedding), c))
+                .collect();
+            scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+            for (rank, (similarity, c)) in scored.into_iter().take(candidates).enumerate() {
+                let hit = results.entry(c.id).or_insert_with(|| SearchResult {
+                    document_id: c.document_id,
+                    title: c.title,
+                    chunk_index: c.chunk_index,
+                    content: c.content,
+                    score: 0.0,
+                    keyword_rank: None,
+                    vector_rank: None,
+                    similarity: None,
+                });
+                hit.vector_rank = Some(rank + 1);
+                hit.similarity = Some(similarity);
+                hit.score += 1.0 / (RRF_K + (rank + 1) as f32);
+            }
+        }
+    }
+
+    let mut results: Vec<SearchResult> = results.into_values().collect();
+    results.sort_by(|a, b| {
+        b.score
+            .total_cmp(&a.score)
+            .then_with(|| a.document_id.cmp(&b.document_id))
+            .then_with(|| a.chunk_index.cmp(&b.chunk_index))
+    });
+    results.truncate(limit);
+    Ok(results)
+}
+
+pub fn search_knowledge(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let search_query: SearchQuery = serde_qs::from_str(req.uri().query().unwrap_or_default())
+        .map_err(|e| ApiError::BadRequest(format!("Invalid query: {}", e)))?;
+    if search_query.q.trim().is_empty() {
+        return Err(ApiError::BadRequest("Query `q` must not be empty".to_string()).into());
+    }
+
+    let limit = search_query.limit.unwrap_or(DEFAULT_LIMIT).clamp(1, 50) as usize;
+    let mode = search_query.mode.unwrap_or(SearchMode::Hybrid);
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+    let results = search(&db, &app.name, &search_query.q, mode, limit)?;
+
+    let body = serde_json::to_vec(&results)?;
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(body.into()))
+        .unwrap())
+}
+
+impl Db {
+    /// Chunks matching the FTS5 query ordered by BM25, best first.
+    pub fn keyword_search(
+        &self,
+        app_name: &str,
+        fts_query: &str,
+        limit: usize,
+    ) -> Result<Vec<(i64, SearchResult)>> {
+        let result = self.conn.execute(
+            "SELECT c.id, c.document_id, d.title, c.chunk_index, c.content
+      FROM document_chunks_fts f
+      JOIN document_chunks c ON c.id = f.rowid
+      JOIN documents d ON d.id = c.document_id
+      WHERE document_chunks_fts MATCH ? AND c.app_name = ?
+      ORDER BY bm25(document_chunks_fts)
+      LIMIT ?",
+            &[
+                ValueParam::Text(fts_query),
+                ValueParam::Text(app_name),
+                ValueParam::Integer(limit as i64),
+            ],
+        )?;
+
+        let col_map = Self::get_column_lookup(&result.columns);
+
+        Ok(result
+            .rows
+            .iter()
+            .map(|r| {
+                (
+                    r.get::<i64>(col_map["id"]).unwrap_or_default(),
+                    SearchResult {
+                        document_id: r.get::<i64>(col_map["document_id"]).unwrap_or_default(),
+                        title: r
+                            .get::<&str>(col_map["title"])
+                            .unwrap_or_default()
+                            .to_string(),
+                        chunk_index: r.get::<u32>(col_map["chunk_index"]).unwrap_or_default(),
+                        content: r
+                            .get::<&str>(col_map["content"])
+                            .unwrap_or_default()
+                            .to_string(),
+                        score: 0.0,
+                        keyword_rank: None,
+                        vector_rank: None,
+                        similarity: None,
+                    },
+                )
+            })
+            .collect())
+    }
+}
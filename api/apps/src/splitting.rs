@@ -0,0 +1,472 @@
+//! Splits documents into chunks for embedding. Offsets are byte offsets into the text that was
+//! split, for JSONL that is the record's text rather than the whole upload. Line endings are
+//! normalised to `\n` first so offsets index the normalised text.
+
+use std::borrow::Cow;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use shared::{validation::Validator, ApiError};
+
+/// Tokens are estimated at four characters each, see `shared::estimate_tokens`.
+const CHARS_PER_TOKEN: usize = 4;
+pub const DEFAULT_CHUNK_SIZE: u32 = 256;
+pub const DEFAULT_OVERLAP: u32 = 32;
+const MIN_CHUNK_SIZE: u32 = 16;
+const MAX_CHUNK_SIZE: u32 = 2048;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum DocumentFormat {
+    Text,
+    Markdown,
+    Jsonl,
+}
+
+impl DocumentFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            DocumentFormat::Text => "text",
+            DocumentFormat::Markdown => "markdown",
+            DocumentFormat::Jsonl => "jsonl",
+        }
+    }
+
+    pub fn parse(format: &str) -> Option<Self> {
+        match format {
+            "text" => Some(DocumentFormat::Text),
+            "markdown" => Some(DocumentFormat::Markdown),
+            "jsonl" => Some(DocumentFormat::Jsonl),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SplitStrategy {
+    /// Fixed windows of `chunk_size` tokens, each overlapping the previous by `overlap` tokens.
+    Tokens,
+    /// One chunk per Markdown section, long sections fall back to paragraphs.
+    Headings,
+    /// Consecutive paragraphs packed into chunks of at most `chunk_size` tokens.
+    Paragraphs,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SplitOptions {
+    pub strategy: SplitStrategy,
+    pub chunk_size: u32,
+    pub overlap: u32,
+}
+
+impl SplitOptions {
+    /// Fills in the defaults for `format`, Markdown is split on headings and everything else on
+    /// paragraphs.
+    pub fn resolve(
+        format: DocumentFormat,
+        strategy: Option<SplitStrategy>,
+        chunk_size: Option<u32>,
+        overlap: Option<u32>,
+    ) -> Result<Self, ApiError> {
+        let options = Self {
+            strategy: strategy.unwrap_or(match format {
+                DocumentFormat::Markdown => SplitStrategy::Headings,
+                DocumentFormat::Text | DocumentFormat::Jsonl => SplitStrategy::Paragraphs,
+            }),
+            chunk_size: chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE),
+            overlap: overlap.unwrap_or(DEFAULT_OVERLAP),
+        };
+
+        let mut v = Validator::new();
+        v.check(
+            "chunk_size",
+            (MIN_CHUNK_SIZE..=MAX_CHUNK_SIZE).contains(&options.chunk_size),
+            &format!("must be between {} and {}", MIN_CHUNK_SIZE, MAX_CHUNK_SIZE),
+        );
+        v.check(
+            "overlap",
+            options.overlap < options.chunk_size,
+            "must be less than chunk_size",
+        );
+        v.finish()?;
+        Ok(options)
+    }
+
+    fn max_chars(&self) -> usize {
+        self.chunk_size as usize * CHARS_PER_TOKEN
+    }
+
+    fn overlap_chars(&self) -> usize {
+        self.overlap as usize * CHARS_PER_TOKEN
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct TextChunk {
+    pub content: String,
+    pub start_offset: usize,
+    pub end_offset: usize,
+    pub metadata: Map<String, Value>,
+}
+
+/// Converts `\r\n` and lone `\r` line endings to `\n`, paragraphs are only found between blank
+/// lines made of `\n`.
+pub fn normalize_line_endings(text: &str) -> Cow<'_, str> {
+    if text.contains('\r') {
+        Cow::Owned(text.replace("\r\n", "\n").replace('\r', "\n"))
+    } else {
+        Cow::Borrowed(text)
+    }
+}
+
+/// Splits a whole upload, JSONL records are split one at a time with their other fields and line
+/// number copied onto each chunk's metadata.
+pub fn split_document(
+    content: &str,
+    format: DocumentFormat,
+    options: &SplitOptions,
+) -> Result<Vec<TextChunk>, ApiError> {
+    let content = normalize_line_endings(content);
+    if format != DocumentFormat::Jsonl {
+        return Ok(split(&content, options));
+    }
+
+    let mut chunks = Vec::new();
+    let mut v = Validator::new();
+    for (i, line) in content.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let field = format!("content[{}]", i + 1);
+        let mut record = match serde_json::from_str::<Value>(line) {
+            Ok(Value::Object(record)) => record,
+            _ => {
+                v.check(&field, false, "must be a JSON object");
+                continue;
+            }
+        };
+        let text = match record.remove("text").or_else(|| record.remove("content")) {
+            Some(Value::String(text)) => text,
+            _ => {
+                v.check(&field, false, "must have a string text or content field");
+                continue;
+            }
+        };
+
+        record.insert("line".to_string(), Value::from(i + 1));
+        for mut chunk in split(&text, options) {
+            for (k, value) in &record {
+                chunk.metadata.insert(k.clone(), value.clone());
+            }
+            chunks.push(chunk);
+        }
+    }
+    v.finish()?;
+    Ok(chunks)
+}
+
+pub fn split(text: &str, options: &SplitOptions) -> Vec<TextChunk> {
+    match options.strategy {
+        SplitStrategy::Tokens => windows(text, 0, options.max_chars(), options.overlap_chars()),
+        SplitStrategy::Headings => headings(text, options.max_chars()),
+        SplitStrategy::Paragraphs => paragraphs(text, 0, options.max_chars()),
+    }
+}
+
+/// Narrows `start..end` to exclude surrounding whitespace, `None` when nothing is left.
+fn trim_span(text: &str, start: usize, end: usize) -> Option<(usize, usize)> {
+    let slice = &text[start..end];
+    let trimmed = slice.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+    let start = start + (slice.len() - slice.trim_start().len());
+    Some((start, start + trimmed.len()))
+}
+
+fn chunk(text: &str, base: usize, start: usize, end: usize) -> Option<TextChunk> {
+    let (start, end) = trim_span(text, start, end)?;
+    Some(TextChunk {
+        content: text[start..end].to_string(),
+        start_offset: base + start,
+        end_offset: base + end,
+        metadata: Map::new(),
+    })
+}
+
+fn floor_char_boundary(text: &str, mut i: usize) -> usize {
+    while !text.is_char_boundary(i) {
+        i -= 1;
+    }
+    i
+}
+
+fn windows(text: &str, base: usize, max_chars: usize, overlap_chars: usize) -> Vec<TextChunk> {
+    let mut chunks = Vec::new();
+    let mut start = 0;
+    while start < text.len() {
+        let mut end = floor_char_boundary(text, (start + max_chars).min(text.len()));
+        if end <= start {
+            // a single character wider than the window
+            end = start + text[start..].chars().next().map_or(1, char::len_utf8);
+        }
+        // prefer to break on whitespace as long as the window stays at least half full
+        if end < text.len() {
+            if let Some(ws) = text[start..end].rfind(char::is_whitespace) {
+                if ws > max_chars / 2 {
+                    end = start + ws;
+                }
+            }
+        }
+        chunks.extend(chunk(text, base, start, end));
+        if end >= text.len() {
+            break;
+        }
+
+        let mut next = floor_char_boundary(text, end.saturating_sub(overlap_chars)).max(start + 1);
+        next = floor_char_boundary(text, next);
+        if next <= start {
+            next = end;
+        }
+        // start the next window on a word rather than part way through one
+        if let Some(ws) = text[next..end].find(char::is_whitespace) {
+            if next > 0 && !text[..next].ends_with(char::is_whitespace) {
+                next += ws;
+            }
+        }
+        start = next;
+    }
+    chunks
+}
+
+fn paragraphs(text: &str, base: usize, max_chars: usize) -> Vec<TextChunk> {
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    for part in text.split("\n\n") {
+        if let Some(span) = trim_span(text, pos, pos + part.len()) {
+            spans.push(span);
+        }
+        pos += part.len() + 2;
+    }
+
+    let mut chunks = Vec::new();
+    let mut current: Option<(usize, usize)> = None;
+    for (start, end) in spans {
+        if let Some((current_start, current_end)) = current {
+            if end - current_start <= max_chars {
+                current = Some((current_start, end));
+                continue;
+            }
+            chunks.extend(chunk(text, base, current_start, current_end));
+            current = None;
+        }
+        if end - start > max_chars {
+            chunks.extend(windows(&text[start..end], base + start, max_chars, 0));
+        } else {
+            current = Some((start, end));
+        }
+    }
+    if let Some((start, end)) = current {
+        chunks.extend(chunk(text, base, start, end));
+    }
+    chunks
+}
+
+/// The level of an ATX heading such as `## Setup`.
+fn heading_level(line: &str) -> Option<(usize, &str)> {
+    let hashes = line.chars().take_while(|c| *c == '#').count();
+    let rest = &line[hashes..];
+    if (1..=6).contains(&hashes) && (rest.is_empty() || rest.starts_with(char::is_whitespace)) {
+        Some((hashes, rest.trim().trim_end_matches('#').trim()))
+    } else {
+        None
+    }
+}
+
+fn headings(text: &str, max_chars: usize) -> Vec<TextChunk> {
+    // (start offset, heading path) of each section, the first may have no heading
+    let mut sections: Vec<(usize, Vec<String>)> = vec![(0, Vec::new())];
+    let mut path: Vec<(usize, String)> = Vec::new();
+    let mut in_fence = false;
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        let trimmed = line.trim_start();
+        if trimmed.starts_with("```") || trimmed.starts_with("~~~") {
+            in_fence = !in_fence;
+        } else if !in_fence {
+            if let Some((level, title)) = heading_level(trimmed) {
+                path.retain(|(l, _)| *l < level);
+                path.push((level, title.to_string()));
+                sections.push((pos, path.iter().map(|(_, t)| t.clone()).collect()));
+            }
+        }
+        pos += line.len();
+    }
+
+    let mut chunks = Vec::new();
+    for (i, (start, path)) in sections.iter().enumerate() {
+        let end = sections.get(i + 1).map_or(text.len(), |(s, _)| *s);
+        let section = &text[*start..end];
+        let mut section_chunks = if section.len() > max_chars {
+            paragraphs(section, *start, max_chars)
+        } else {
+            chunk(text, 0, *start, end).into_iter().collect()
+        };
+        if !path.is_empty() {
+            for c in &mut section_chunks {
+                c.metadata
+                    .insert("headings".to_string(), Value::from(path.clone()));
+            }
+        }
+        chunks.extend(section_chunks);
+    }
+    chunks
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(strategy: SplitStrategy, chunk_size: u32, overlap: u32) -> SplitOptions {
+        SplitOptions {
+            strategy,
+            chunk_size,
+            overlap,
+        }
+    }
+
+    fn assert_offsets(text: &str, chunks: &[TextChunk]) {
+        for c in chunks {
+            assert_eq!(&text[c.start_offset..c.end_offset], c.content);
+        }
+    }
+
+    #[test]
+    fn windows_break_on_char_boundaries() {
+        // 3 byte characters with no whitespace, so every window edge falls inside one
+        let text = "€".repeat(100);
+        let chunks = split(&text, &options(SplitStrategy::Tokens, 16, 0));
+
+        assert!(chunks.len() > 1);
+        assert_offsets(&text, &chunks);
+        assert_eq!(chunks.first().unwrap().start_offset, 0);
+        assert_eq!(chunks.last().unwrap().end_offset, text.len());
+        for pair in chunks.windows(2) {
+            assert_eq!(pair[0].end_offset, pair[1].start_offset);
+        }
+    }
+
+    #[test]
+    fn windows_overlap_and_start_on_words() {
+        let text = (0..100)
+            .map(|i| format!("word{:02}", i))
+            .collect::<Vec<_>>()
+            .join(" ");
+        let chunks = split(&text, &options(SplitStrategy::Tokens, 16, 4));
+
+        assert!(chunks.len() > 1);
+        assert_offsets(&text, &chunks);
+        for pair in chunks.windows(2) {
+            assert!(pair[1].start_offset < pair[0].end_offset);
+            assert!(pair[1].start_offset > pair[0].start_offset);
+            assert!(pair[1].content.starts_with("word"));
+        }
+        assert_eq!(chunks.last().unwrap().end_offset, text.len());
+    }
+
+    #[test]
+    fn long_paragraph_falls_back_to_windows() {
+        let long = "lorem ipsum ".repeat(30);
+        let text = format!("short para\n\n{}\n\nend", long.trim());
+        let chunks = split(&text, &options(SplitStrategy::Paragraphs, 16, 0));
+
+        assert_offsets(&text, &chunks);
+        assert_eq!(chunks.first().unwrap().content, "short para");
+        assert_eq!(chunks.last().unwrap().content, "end");
+        assert!(chunks.len() > 3);
+        assert!(chunks.iter().all(|c| c.content.len() <= 64));
+    }
+
+    #[test]
+    fn paragraphs_are_packed_into_chunks() {
+        let text = "one\n\ntwo\n\nthree";
+        let chunks = split(text, &options(SplitStrategy::Paragraphs, 16, 0));
+
+        assert_eq!(chunks.len(), 1);
+        assert_eq!(chunks[0].content, text);
+    }
+
+    #[test]
+    fn headings_ignore_fenced_code() {
+        let text = "# Setup\n\n```sh\n# not a heading\nmake\n```\n\n## Run\n\nrun it";
+        let chunks = split(text, &options(SplitStrategy::Headings, 256, 0));
+
+        assert_offsets(text, &chunks);
+        assert_eq!(chunks.len(), 2);
+        assert!(chunks[0].content.contains("# not a heading"));
+        assert_eq!(chunks[0].metadata["headings"], serde_json::json!(["Setup"]));
+        assert_eq!(chunks[1].content, "## Run\n\nrun it");
+        assert_eq!(
+            chunks[1].metadata["headings"],
+            serde_json::json!(["Setup", "Run"])
+        );
+    }
+
+    #[test]
+    fn jsonl_fields_are_copied_to_metadata() {
+        let content = "{\"text\": \"hello\", \"source\": \"a\"}\n\n{\"content\": \"world\", \"tags\": [\"x\"]}\n";
+        let chunks = split_document(
+            content,
+            DocumentFormat::Jsonl,
+            &options(SplitStrategy::Paragraphs, 256, 0),
+        )
+        .unwrap();
+
+        assert_eq!(chunks.len(), 2);
+        assert_eq!(chunks[0].content, "hello");
+        assert_eq!(
+            Value::Object(chunks[0].metadata.clone()),
+            serde_json::json!({"source": "a", "line": 1})
+        );
+        assert_eq!(chunks[1].content, "world");
+        assert_eq!(
+            Value::Object(chunks[1].metadata.clone()),
+            serde_json::json!({"tags": ["x"], "line": 3})
+        );
+    }
+
+    #[test]
+    fn jsonl_reports_bad_lines() {
+        let content = "{\"text\": \"hello\"}\n[1, 2]\n{\"title\": \"no text\"}";
+        let result = split_document(
+            content,
+            DocumentFormat::Jsonl,
+            &options(SplitStrategy::Paragraphs, 256, 0),
+        );
+
+        assert!(matches!(result, Err(ApiError::Validation(_))));
+    }
+
+    #[test]
+    fn crlf_paragraphs_are_split() {
+        let a = "a".repeat(40);
+        let b = "b".repeat(40);
+        let content = format!("{}\r\n\r\n{}\r\n", a, b);
+        let chunks = split_document(
+            &content,
+            DocumentFormat::Text,
+            &options(SplitStrategy::Paragraphs, 16, 0),
+        )
+        .unwrap();
+
+        assert_offsets(&normalize_line_endings(&content), &chunks);
+        assert_eq!(
+            chunks
+                .iter()
+                .map(|c| c.content.as_str())
+                .collect::<Vec<_>>(),
+            vec![a.as_str(), b.as_str()]
+        );
+    }
+}
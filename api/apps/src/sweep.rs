@@ -0,0 +1,198 @@
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+use shared::{
+    parse_json, sweep::ParamSweep, validation::Validator, ApiError, GenerationMessage,
+    GenerationModelParams, GenerationSource, GenerationUsage, InferSdkBuilder,
+};
+use spin_sdk::http::{Params, Request, Response};
+
+use crate::{completions, data::Db, evals, knowledge, model::App, validate_messages};
+
+/// Inference calls one sweep may make, combinations times eval cases.
+const MAX_RUNS_PER_SWEEP: usize = 100;
+
+#[derive(Debug, Deserialize)]
+pub struct SweepRequest {
+    pub params: ParamSweep,
+    /// A fixed prompt to run at every point, the app's eval cases are run when this is empty.
+    #[serde(default)]
+    pub messages: Vec<GenerationMessage>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct SweepCase {
+    pub case_id: i64,
+    pub passed: bool,
+}
+
+/// The results for one combination of params. Eval sweeps report `score` and whether each case
+/// passed, prompt sweeps report the `output`.
+#[derive(Debug, Serialize)]
+pub struct SweepPoint {
+    /// Every param the point ran with, not only the swept ones.
+    pub params: GenerationModelParams,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub score: Option<f64>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub cases: Vec<SweepCase>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub output: Option<String>,
+    /// The first completion that failed, failed eval cases are also counted against the score.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// Summed over the eval cases.
+    pub usage: GenerationUsage,
+    pub duration_ms: u64,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Sweep {
+    pub app_name: String,
+    pub total_runs: usize,
+    pub points: Vec<SweepPoint>,
+    /// Index into `points` of the highest score, ties go to the faster point. Eval sweeps only.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub best: Option<usize>,
+}
+
+fn point(params: GenerationModelParams) -> SweepPoint {
+    SweepPoint {
+        params,
+        score: None,
+        cases: Vec::new(),
+        output: None,
+        error: None,
+        usage: GenerationUsage {
+            prompt_token_count: 0,
+            generated_token_count: 0,
+        },
+        duration_ms: 0,
+    }
+}
+
+fn eval_point(db: &Db, app: &App, cases: &[evals::EvalCase]) -> SweepPoint {
+    let mut point = point((&app.model).into());
+    let results = cases.iter().map(|c| evals::run_case(db, app, c)).collect();
+    let run = evals::summarize(&app.name, results);
+
+    for case in &run.cases {
+        if let Some(usage) = &case.usage {
+            point.usage.prompt_token_count += usage.prompt_token_count;
+            point.usage.generated_token_count += usage.generated_token_count;
+        }
+        point.duration_ms += case.duration_ms;
+        if point.error.is_none() {
+            point.error = case.error.clone();
+        }
+    }
+    point.score = Some(run.score);
+    point.cases = run
+        .cases
+        .iter()
+        .map(|c| SweepCase {
+            case_id: c.case_id,
+            passed: c.passed,
+        })
+        .collect();
+    point
+}
+
+fn prompt_point(
+    app: &App,
+    messages: &[GenerationMessage],
+    sources: &[GenerationSource],
+) -> SweepPoint {
+    let mut point = point((&app.model).into());
+    match completions::infer(app, messages.to_vec(), sources.to_vec()) {
+        Ok(completion) => {
+            point.output = Some(completion.output);
+            point.usage = completion.usage;
+            point.duration_ms = completion.duration_ms;
+        }
+        Err(e) => point.error = Some(ApiError::from(&e).to_string()),
+    }
+    point
+}
+
+/// Runs the app at every combination of the swept params, over the fixed prompt when one is
+/// given and over the app's eval cases otherwise. Nothing is stored and the app is unchanged.
+pub fn run_sweep(req: Request, params: Params) -> Result<Response> {
+    let name = params
+        .get("name")
+        .context("url route missing name parameter.")?;
+    let req_body = match req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let sweep_req = parse_json::<SweepRequest>(&req_body)?;
+    let mut v = Validator::new();
+    validate_messages(&mut v, &sweep_req.messages);
+    v.finish()?;
+
+    let db = Db::new()?;
+    let app = db.get(name)?;
+
+    let grid = InferSdkBuilder::new()
+        .with_model(Some(app.model.name.clone()))
+        .with_params(Some((&app.model).into()))
+        .build_param_grid(&sweep_req.params)?;
+
+    let cases = match sweep_req.messages.is_empty() {
+        true => db.list_eval_cases(&app.name)?,
+        false => Vec::new(),
+    };
+    if sweep_req.messages.is_empty() && cases.is_empty() {
+        return Err(ApiError::Unprocessable(format!(
+            "App '{}' has no eval cases, pass messages to sweep over a fixed prompt",
+            app.name
+        ))
+        .into());
+    }
+
+    let total_runs = grid.len() * cases.len().max(1);
+    if total_runs > MAX_RUNS_PER_SWEEP {
+        return Err(ApiError::Unprocessable(format!(
+            "Sweep needs {} runs, at most {} can be run in one request",
+            total_runs, MAX_RUNS_PER_SWEEP
+        ))
+        .into());
+    }
+
+    // the prompt is the same at every point so its sources only need retrieving once
+    let sources = match sweep_req.messages.is_empty() {
+        true => Vec::new(),
+        false => knowledge::retrieve(&db, &app.name, &sweep_req.messages)?,
+    };
+
+    let points: Vec<SweepPoint> = grid
+        .into_iter()
+        .map(|params| {
+            let mut app = app.clone();
+            app.model = app.model.with_params(&params.into());
+            match cases.is_empty() {
+                true => prompt_point(&app, &sweep_req.messages, &sources),
+                false => eval_point(&db, &app, &cases),
+            }
+        })
+        .collect();
+
+    let best = points
+        .iter()
+        .enumerate()
+        .filter_map(|(i, p)| p.score.map(|score| (i, score, p.duration_ms)))
+        .max_by(|a, b| a.1.total_cmp(&b.1).then_with(|| b.2.cmp(&a.2)))
+        .map(|(i, _, _)| i);
+
+    let sweep = Sweep {
+        app_name: app.name.clone(),
+        total_runs,
+        points,
+        best,
+    };
+
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(serde_json::to_vec(&sweep)?.into()))
+        .unwrap())
+}
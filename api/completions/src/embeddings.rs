@@ -0,0 +1,109 @@
+// Embeddings in the OpenAI response shape -> https://platform.openai.com/docs/api-reference/embeddings
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use shared::{embedding_model, parse_json, validation::Validator};
+use spin_sdk::http::{Params, Request, Response};
+
+const MAX_INPUTS: usize = 256;
+
+#[derive(Debug, Deserialize)]
+pub struct EmbeddingRequest {
+    pub model: String,
+    pub input: EmbeddingInput,
+}
+
+/// `input` may be sent as a single string or a list of strings.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum EmbeddingInput {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl EmbeddingInput {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            EmbeddingInput::One(s) => vec![s],
+            EmbeddingInput::Many(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmbeddingResponse {
+    pub object: String,
+    pub data: Vec<Embedding>,
+    pub model: String,
+    pub usage: EmbeddingUsage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Embedding {
+    pub object: String,
+    pub index: usize,
+    pub embedding: Vec<f32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct EmbeddingUsage {
+    pub prompt_tokens: u32,
+    pub total_tokens: u32,
+}
+
+pub fn handle_embeddings(http_req: Request, _params: Params) -> Result<Response> {
+    let body = match http_req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let api_req: EmbeddingRequest = parse_json(&body)?;
+    let input = api_req.input.into_vec();
+
+    let mut v = Validator::new();
+    v.check(
+        "input",
+        !input.is_empty(),
+        "must contain at least one string",
+    );
+    v.check(
+        "input",
+        input.len() <= MAX_INPUTS,
+        &format!("must not contain more than {} strings", MAX_INPUTS),
+    );
+    for (i, text) in input.iter().enumerate() {
+        v.check(
+            &format!("input[{}]", i),
+            !text.is_empty(),
+            "must not be empty",
+        );
+    }
+    v.finish()?;
+
+    let model = embedding_model(&api_req.model)?;
+    let result = spin_sdk::llm::generate_embeddings(model, &input)?;
+
+    let response = EmbeddingResponse {
+        object: "list".to_string(),
+        data: result
+            .embeddings
+            .into_iter()
+            .enumerate()
+            .map(|(index, embedding)| Embedding {
+                object: "embedding".to_string(),
+                index,
+                embedding,
+            })
+            .collect(),
+        model: api_req.model,
+        usage: EmbeddingUsage {
+            prompt_tokens: result.usage.prompt_token_count,
+            total_tokens: result.usage.prompt_token_count,
+        },
+    };
+
+    let body = serde_json::to_vec(&response)?;
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(body.into()))
+        .unwrap())
+}
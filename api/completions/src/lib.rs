@@ -1,10 +1,13 @@
 use anyhow::Result;
-use shared::{GenerationRequest, GenerationResponseBuilder, InferSdkBuilder, llama2_prompt::Llama2PromptBuilder};
+use shared::{error_response, validation, GenerationRequest, GenerationResponseBuilder, InferSdkBuilder, PromptBuilderRegistry};
 use spin_sdk::{
     http::{Params, Request, Response, Router},
     http_component,
 };
 
+mod embeddings;
+mod openai;
+
 #[http_component]
 fn handle(req: Request) -> Result<Response> {
     let component_route = req
@@ -14,29 +17,54 @@ fn handle(req: Request) -> Result<Response> {
         .to_str()?;
     let mut router = Router::new();
     router.post(&format!("{}/completions", component_route), handle_completion);
-    router.handle(req)
+    router.post(&format!("{}/completions/preview", component_route), handle_preview);
+    router.post(&format!("{}/embeddings", component_route), embeddings::handle_embeddings);
+    router.post(&format!("{}/v1/chat/completions", component_route), openai::handle_chat_completion);
+    router.post(&format!("{}/v1/completions", component_route), openai::handle_text_completion);
+    router.handle(req).or_else(|e| Ok(error_response(&e)))
+}
+
+/// Builds the prompt for a completion request without running inference.
+fn handle_preview(http_req: Request, _params: Params) -> Result<Response> {
+  let api_req = GenerationRequest::try_from(http_req)?;
+  validation::validate_request(&api_req)?;
+
+  let preview = InferSdkBuilder::new()
+    .with_model(api_req.model)
+    .with_messages(api_req.messages)
+    .with_raw_prompt(api_req.prompt)
+    .with_params(api_req.params)
+    .with_prompt_builders(PromptBuilderRegistry::from_config()?)
+    .build_preview()?;
+
+  let body = serde_json::to_vec(&preview)?;
+  Ok(http::Response::builder()
+    .status(200)
+    .header(http::header::CONTENT_TYPE, "application/json")
+    .body(Some(body.into()))
+    .unwrap())
 }
 
 fn handle_completion(http_req: Request, _params: Params) -> Result<Response> {
   // parse the request
   let api_req = GenerationRequest::try_from(http_req)?;
+  validation::validate_request(&api_req)?;
 
   let sdk_bldr = InferSdkBuilder::new()
     .with_model(api_req.model)
     .with_messages(api_req.messages)
+    .with_raw_prompt(api_req.prompt)
     .with_params(api_req.params)
-    //TODO: should be able to select this based on the model but I just want to get this done
-    .with_prompt_builder(Box::new(Llama2PromptBuilder {}));
+    .with_prompt_builders(PromptBuilderRegistry::from_config()?);
 
   let model = sdk_bldr.build_model()?;
-  let prompt = sdk_bldr.build_prompt()?;
+  let truncated = sdk_bldr.build_truncated_prompt()?;
+  let prompt = truncated.prompt;
   let params = sdk_bldr.build_params();
 
   let infer_result = spin_sdk::llm::infer_with_options(model, &prompt, params)?;
 
-  println!("{}, Formatted prompt: {:?}",
-    chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
-    prompt);
-
-  GenerationResponseBuilder::new(infer_result).build()
+  GenerationResponseBuilder::new(infer_result)
+    .with_dropped_messages(truncated.dropped_messages)
+    .build()
 }
@@ -0,0 +1,302 @@
+// OpenAI compatible chat completions -> https://platform.openai.com/docs/api-reference/chat
+// and legacy text completions -> https://platform.openai.com/docs/api-reference/completions
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use shared::{
+    validation::{validate_params, Validator},
+    parse_json, ApiError, GenerationMessage, GenerationModelParams, InferSdkBuilder,
+    PromptBuilderRegistry,
+};
+use spin_sdk::{
+    http::{Params, Request, Response},
+    llm::{InferencingModel, InferencingParams},
+};
+
+/// Upper bound on `n`, each choice is a separate inference call.
+const MAX_CHOICES: u32 = 8;
+
+#[derive(Debug, Deserialize)]
+pub struct ChatCompletionRequest {
+    pub model: String,
+    pub messages: Vec<GenerationMessage>,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop: Option<StopSequences>,
+    pub n: Option<u32>,
+}
+
+/// `stop` may be sent as a single string or a list of strings.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum StopSequences {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl StopSequences {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            StopSequences::One(s) => vec![s],
+            StopSequences::Many(v) => v,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatCompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<ChatCompletionChoice>,
+    pub usage: ChatCompletionUsage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatCompletionChoice {
+    pub index: u32,
+    pub message: GenerationMessage,
+    pub finish_reason: String,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct TextCompletionRequest {
+    pub model: String,
+    pub prompt: String,
+    pub temperature: Option<f32>,
+    pub top_p: Option<f32>,
+    pub max_tokens: Option<u32>,
+    pub stop: Option<StopSequences>,
+    pub n: Option<u32>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TextCompletionResponse {
+    pub id: String,
+    pub object: String,
+    pub created: i64,
+    pub model: String,
+    pub choices: Vec<TextCompletionChoice>,
+    pub usage: ChatCompletionUsage,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TextCompletionChoice {
+    pub index: u32,
+    pub text: String,
+    pub finish_reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct ChatCompletionUsage {
+    pub prompt_tokens: u32,
+    pub completion_tokens: u32,
+    pub total_tokens: u32,
+}
+
+// the sdk has no stop sequence support so the output is truncated after inferencing
+fn apply_stop(text: &str, stop: &[String]) -> Option<String> {
+    stop.iter()
+        .filter(|s| !s.is_empty())
+        .filter_map(|s| text.find(s.as_str()))
+        .min()
+        .map(|i| text[..i].to_string())
+}
+
+// errors use the OpenAI envelope rather than the explorer's own error body
+fn error_response(e: &anyhow::Error) -> Response {
+    let api_error = ApiError::from(e);
+    let error_type = match api_error.status().as_u16() {
+        400..=499 => "invalid_request_error",
+        _ => "api_error",
+    };
+    // OpenAI reports invalid fields as a 400 naming the first offending param
+    let (status, param) = match &api_error {
+        ApiError::Validation(errors) => (
+            http::StatusCode::BAD_REQUEST,
+            errors.first().map(|e| e.field.clone()),
+        ),
+        _ => (api_error.status(), None),
+    };
+    let body = serde_json::json!({
+        "error": {
+            "message": api_error.to_string(),
+            "type": error_type,
+            "param": param,
+            "code": api_error.code(),
+        }
+    });
+    http::Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(body.to_string().into()))
+        .unwrap()
+}
+
+/// Runs inference `n` times, returning each output with its finish reason.
+fn generate(
+    model: InferencingModel,
+    prompt: &str,
+    params: InferencingParams,
+    n: u32,
+    stop: &[String],
+) -> Result<(Vec<(String, &'static str)>, ChatCompletionUsage)> {
+    let mut outputs = Vec::new();
+    let mut usage = ChatCompletionUsage {
+        prompt_tokens: 0,
+        completion_tokens: 0,
+        total_tokens: 0,
+    };
+    for _ in 0..n {
+        let infer_result = spin_sdk::llm::infer_with_options(model, prompt, params)?;
+
+        let output = match apply_stop(&infer_result.text, stop) {
+            Some(truncated) => (truncated, "stop"),
+            None if infer_result.usage.generated_token_count >= params.max_tokens => {
+                (infer_result.text, "length")
+            }
+            None => (infer_result.text, "stop"),
+        };
+
+        usage.prompt_tokens = infer_result.usage.prompt_token_count;
+        usage.completion_tokens += infer_result.usage.generated_token_count;
+        outputs.push(output);
+    }
+    usage.total_tokens = usage.prompt_tokens + usage.completion_tokens;
+    Ok((outputs, usage))
+}
+
+fn json_response<T: Serialize>(body: &T) -> Result<Response> {
+    let body = serde_json::to_vec(body)?;
+    Ok(http::Response::builder()
+        .status(http::StatusCode::OK)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .body(Some(body.into()))
+        .unwrap())
+}
+
+pub fn handle_text_completion(http_req: Request, _params: Params) -> Result<Response> {
+    text_completion(http_req).or_else(|e| Ok(error_response(&e)))
+}
+
+pub fn handle_chat_completion(http_req: Request, _params: Params) -> Result<Response> {
+    chat_completion(http_req).or_else(|e| Ok(error_response(&e)))
+}
+
+fn chat_completion(http_req: Request) -> Result<Response> {
+    let body = match http_req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let api_req: ChatCompletionRequest = parse_json(&body)?;
+
+    let model_name = api_req.model.clone();
+    let n = api_req.n.unwrap_or(1);
+    let stop = api_req.stop.map(|s| s.into_vec()).unwrap_or_default();
+
+    let params = GenerationModelParams {
+        max_tokens: api_req.max_tokens,
+        repeat_penalty: None,
+        repeat_penalty_last_n_token_count: None,
+        temperature: api_req.temperature,
+        top_k: None,
+        top_p: api_req.top_p,
+    };
+    let mut v = Validator::new();
+    v.check("messages", !api_req.messages.is_empty(), "must contain at least one message");
+    v.check("n", (1..=MAX_CHOICES).contains(&n), &format!("must be between 1 and {}", MAX_CHOICES));
+    validate_params(&mut v, &api_req.model, &params, |f| f.to_string());
+    v.finish()?;
+
+    let sdk_bldr = InferSdkBuilder::new()
+        .with_model(Some(api_req.model))
+        .with_messages(api_req.messages)
+        .with_params(Some(params))
+        .with_prompt_builders(PromptBuilderRegistry::from_config()?);
+
+    let model = sdk_bldr.build_model()?;
+    let prompt = sdk_bldr.build_prompt()?;
+    let params = sdk_bldr.build_params();
+
+    let (outputs, usage) = generate(model, &prompt, params, n, &stop)?;
+    let choices = outputs
+        .into_iter()
+        .zip(0..)
+        .map(|((content, finish_reason), index)| ChatCompletionChoice {
+            index,
+            message: GenerationMessage {
+                role: "assistant".to_string(),
+                content,
+            },
+            finish_reason: finish_reason.to_string(),
+        })
+        .collect();
+
+    let now = chrono::Utc::now();
+    json_response(&ChatCompletionResponse {
+        id: format!("chatcmpl-{}", now.timestamp_nanos_opt().unwrap_or_default()),
+        object: "chat.completion".to_string(),
+        created: now.timestamp(),
+        model: model_name,
+        choices,
+        usage,
+    })
+}
+
+// the prompt is passed through verbatim, no prompt builder is applied
+fn text_completion(http_req: Request) -> Result<Response> {
+    let body = match http_req.body() {
+        Some(b) => b.to_vec(),
+        None => Default::default(),
+    };
+    let api_req: TextCompletionRequest = parse_json(&body)?;
+
+    let model_name = api_req.model.clone();
+    let n = api_req.n.unwrap_or(1);
+    let stop = api_req.stop.map(|s| s.into_vec()).unwrap_or_default();
+
+    let params = GenerationModelParams {
+        max_tokens: api_req.max_tokens,
+        repeat_penalty: None,
+        repeat_penalty_last_n_token_count: None,
+        temperature: api_req.temperature,
+        top_k: None,
+        top_p: api_req.top_p,
+    };
+    let mut v = Validator::new();
+    v.check("prompt", !api_req.prompt.is_empty(), "must not be empty");
+    v.check("n", (1..=MAX_CHOICES).contains(&n), &format!("must be between 1 and {}", MAX_CHOICES));
+    validate_params(&mut v, &api_req.model, &params, |f| f.to_string());
+    v.finish()?;
+
+    let sdk_bldr = InferSdkBuilder::new()
+        .with_model(Some(api_req.model))
+        .with_raw_prompt(Some(api_req.prompt))
+        .with_params(Some(params));
+
+    let model = sdk_bldr.build_model()?;
+    let prompt = sdk_bldr.build_prompt()?;
+    let params = sdk_bldr.build_params();
+
+    let (outputs, usage) = generate(model, &prompt, params, n, &stop)?;
+    let choices = outputs
+        .into_iter()
+        .zip(0..)
+        .map(|((text, finish_reason), index)| TextCompletionChoice {
+            index,
+            text,
+            finish_reason: finish_reason.to_string(),
+        })
+        .collect();
+
+    let now = chrono::Utc::now();
+    json_response(&TextCompletionResponse {
+        id: format!("cmpl-{}", now.timestamp_nanos_opt().unwrap_or_default()),
+        object: "text_completion".to_string(),
+        created: now.timestamp(),
+        model: model_name,
+        choices,
+        usage,
+    })
+}
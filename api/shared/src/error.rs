@@ -0,0 +1,140 @@
+use std::fmt;
+
+use serde::Serialize;
+use spin_sdk::http::Response;
+
+use crate::{validation::FieldError, ModelNotAllowed};
+
+/// Errors that map onto an HTTP status and a JSON body of the form
+/// `{ "code": "not_found", "message": "App 'foo' not found" }`.
+#[derive(Debug, Clone)]
+pub enum ApiError {
+  BadRequest(String),
+  InvalidJson(String),
+  ModelNotAllowed(ModelNotAllowed),
+  NotFound(String),
+  /// `location` points at the existing resource and is sent back as the `Location` header.
+  Conflict { message: String, location: Option<String> },
+  Unprocessable(String),
+  Validation(Vec<FieldError>),
+  InferenceUnavailable(String),
+  Internal(String),
+}
+
+#[derive(Debug, Serialize)]
+struct ErrorBody<'a> {
+  code: &'a str,
+  message: String,
+  #[serde(skip_serializing_if = "Option::is_none")]
+  details: Option<serde_json::Value>,
+}
+
+impl ApiError {
+  pub fn status(&self) -> http::StatusCode {
+    match self {
+      ApiError::BadRequest(_) | ApiError::InvalidJson(_) | ApiError::ModelNotAllowed(_) => http::StatusCode::BAD_REQUEST,
+      ApiError::NotFound(_) => http::StatusCode::NOT_FOUND,
+      ApiError::Conflict { .. } => http::StatusCode::CONFLICT,
+      ApiError::Unprocessable(_) | ApiError::Validation(_) => http::StatusCode::UNPROCESSABLE_ENTITY,
+      ApiError::InferenceUnavailable(_) => http::StatusCode::SERVICE_UNAVAILABLE,
+      ApiError::Internal(_) => http::StatusCode::INTERNAL_SERVER_ERROR,
+    }
+  }
+
+  /// Machine readable identifier for the error, stable across message wording changes.
+  pub fn code(&self) -> &'static str {
+    match self {
+      ApiError::BadRequest(_) => "bad_request",
+      ApiError::InvalidJson(_) => "invalid_json",
+      ApiError::ModelNotAllowed(_) => "model_not_allowed",
+      ApiError::NotFound(_) => "not_found",
+      ApiError::Conflict { .. } => "conflict",
+      ApiError::Unprocessable(_) => "unprocessable_entity",
+      ApiError::Validation(_) => "validation_failed",
+      ApiError::InferenceUnavailable(_) => "inference_unavailable",
+      ApiError::Internal(_) => "internal_error",
+    }
+  }
+
+  fn details(&self) -> Option<serde_json::Value> {
+    match self {
+      ApiError::ModelNotAllowed(not_allowed) => serde_json::to_value(not_allowed).ok(),
+      ApiError::Validation(errors) => Some(serde_json::json!({ "errors": errors })),
+      _ => None,
+    }
+  }
+
+  pub fn to_response(&self) -> Response {
+    let body = ErrorBody {
+      code: self.code(),
+      message: self.to_string(),
+      details: self.details(),
+    };
+    let mut builder = http::Response::builder()
+      .status(self.status())
+      .header(http::header::CONTENT_TYPE, "application/json");
+    if let ApiError::Conflict { location: Some(location), .. } = self {
+      builder = builder.header(http::header::LOCATION, location);
+    }
+    builder
+      .body(Some(serde_json::to_vec(&body).unwrap_or_default().into()))
+      .unwrap()
+  }
+}
+
+impl fmt::Display for ApiError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ApiError::ModelNotAllowed(not_allowed) => not_allowed.fmt(f),
+      ApiError::Validation(errors) => {
+        let fields: Vec<&str> = errors.iter().map(|e| e.field.as_str()).collect();
+        write!(f, "Invalid fields: {}", fields.join(", "))
+      },
+      ApiError::BadRequest(message)
+      | ApiError::InvalidJson(message)
+      | ApiError::NotFound(message)
+      | ApiError::Conflict { message, .. }
+      | ApiError::Unprocessable(message)
+      | ApiError::InferenceUnavailable(message)
+      | ApiError::Internal(message) => f.write_str(message),
+    }
+  }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<ModelNotAllowed> for ApiError {
+  fn from(not_allowed: ModelNotAllowed) -> Self {
+    ApiError::ModelNotAllowed(not_allowed)
+  }
+}
+
+impl From<&spin_sdk::llm::Error> for ApiError {
+  fn from(e: &spin_sdk::llm::Error) -> Self {
+    match e {
+      spin_sdk::llm::Error::ModelNotSupported => ApiError::BadRequest("Model is not supported by the host".to_string()),
+      spin_sdk::llm::Error::InvalidInput(message) => ApiError::Unprocessable(message.clone()),
+      spin_sdk::llm::Error::RuntimeError(message) => ApiError::InferenceUnavailable(message.clone()),
+    }
+  }
+}
+
+impl From<&anyhow::Error> for ApiError {
+  /// Classifies an error bubbled up from a handler, anything unrecognized becomes a 500.
+  fn from(e: &anyhow::Error) -> Self {
+    for cause in e.chain() {
+      if let Some(api_error) = cause.downcast_ref::<ApiError>() {
+        return api_error.clone();
+      }
+      if let Some(llm_error) = cause.downcast_ref::<spin_sdk::llm::Error>() {
+        return llm_error.into();
+      }
+    }
+    ApiError::Internal(e.to_string())
+  }
+}
+
+/// Renders any handler error as a JSON error response, used at the top of each component.
+pub fn error_response(e: &anyhow::Error) -> Response {
+  ApiError::from(e).to_response()
+}
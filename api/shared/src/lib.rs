@@ -1,15 +1,26 @@
+use std::{collections::HashMap, fmt};
+
 use anyhow::{anyhow, Result, bail};
 use serde::{Serialize, Deserialize};
-use spin_sdk::{http::{Request, Response}, llm::{InferencingModel, InferencingParams, InferencingResult}};
+use spin_sdk::{http::{Request, Response}, llm::{EmbeddingModel, InferencingModel, InferencingParams, InferencingResult}};
+
+pub mod error;
+pub mod sweep;
+pub mod validation;
+pub use error::{ApiError, error_response};
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct GenerationRequest {
     pub model: Option<String>,
+    #[serde(default)]
     pub messages: Vec<GenerationMessage>,
+    /// Sent to the model verbatim in place of `messages`, no prompt builder is applied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prompt: Option<String>,
     pub params: Option<GenerationModelParams>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Default, Serialize, Deserialize)]
 pub struct GenerationModelParams {
   pub max_tokens: Option<u32>,
   pub repeat_penalty: Option<f32>,
@@ -38,6 +49,22 @@ impl Clone for GenerationMessage {
 pub struct GenerationResponse {
     pub messages: Vec<GenerationMessage>,
     pub usage: GenerationUsage,
+    /// Indexes of the request messages that were left out to fit the model's context window.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub dropped_messages: Vec<usize>,
+    /// Knowledge base chunks that were added to the prompt.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub sources: Vec<GenerationSource>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GenerationSource {
+    pub document_id: i64,
+    pub title: String,
+    pub chunk_index: u32,
+    pub content: String,
+    /// Relevance to the query, higher is better.
+    pub score: f32,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -48,10 +75,15 @@ pub struct GenerationUsage {
 
 pub struct GenerationResponseBuilder {
   res: InferencingResult,
+  dropped_messages: Vec<usize>,
 }
 impl GenerationResponseBuilder {
   pub fn new(res: InferencingResult) -> Self {
-    Self { res }
+    Self { res, dropped_messages: Vec::new() }
+  }
+
+  pub fn with_dropped_messages(self, dropped_messages: Vec<usize>) -> Self {
+    Self { dropped_messages, ..self }
   }
 
   pub fn build(self) -> Result<Response> {
@@ -64,6 +96,8 @@ impl GenerationResponseBuilder {
         prompt_token_count: self.res.usage.prompt_token_count,
         generated_token_count: self.res.usage.generated_token_count,
       },
+      dropped_messages: self.dropped_messages,
+      sources: Vec::new(),
     };
     let body = serde_json::to_vec(&response)?;
     Ok(http::Response::builder()
@@ -82,15 +116,71 @@ impl TryFrom<Request> for GenerationRequest {
       Some(b) => b.to_vec(),
       None => Default::default(),
     };
-    let request: GenerationRequest = serde_json::from_slice(&body)?;
+    let request: GenerationRequest = parse_json(&body)?;
     Ok(request)
   }
 }
 
+/// Parses a JSON request body, a malformed body is the client's fault so it becomes a 400 rather
+/// than the 500 any other JSON error gets.
+pub fn parse_json<T: serde::de::DeserializeOwned>(body: &[u8]) -> Result<T, ApiError> {
+  serde_json::from_slice(body).map_err(|e| ApiError::InvalidJson(e.to_string()))
+}
+
 pub trait PromptBuilder {
   fn build_prompt(&self, messages: Vec<GenerationMessage>) -> Result<String>;
 }
 
+type PromptBuilderFactory = Box<dyn Fn() -> Box<dyn PromptBuilder>>;
+
+/// Maps model names to the prompt builder that formats messages for that model.
+pub struct PromptBuilderRegistry {
+  factories: HashMap<String, PromptBuilderFactory>,
+}
+impl PromptBuilderRegistry {
+  pub fn new() -> Self {
+    Self { factories: HashMap::new() }
+  }
+
+  pub fn register(&mut self, model: &str, factory: impl Fn() -> Box<dyn PromptBuilder> + 'static) {
+    self.factories.insert(model.to_string(), Box::new(factory));
+  }
+
+  pub fn register_template(&mut self, model: &str, template: &str) {
+    let template = template.to_string();
+    self.register(model, move || Box::new(template_prompt::TemplatePromptBuilder::new(&template)));
+  }
+
+  pub fn get(&self, model: &str) -> Option<Box<dyn PromptBuilder>> {
+    self.factories.get(model).map(|factory| factory())
+  }
+
+  /// Built-in builders plus any templates from the `chat_templates` Spin variable, which
+  /// holds a JSON object of model name to chat template.
+  pub fn from_config() -> Result<Self> {
+    let mut registry = Self::default();
+    if let Ok(templates) = spin_sdk::config::get("chat_templates") {
+      if !templates.trim().is_empty() {
+        let templates: HashMap<String, String> = serde_json::from_str(&templates)
+          .map_err(|e| anyhow!("Invalid chat_templates variable: {}", e))?;
+        for (model, template) in templates.iter() {
+          registry.register_template(model, template);
+        }
+      }
+    }
+    Ok(registry)
+  }
+}
+
+impl Default for PromptBuilderRegistry {
+  fn default() -> Self {
+    let mut registry = Self::new();
+    registry.register("llama2-chat", || Box::new(llama2_prompt::Llama2PromptBuilder {}));
+    registry.register("codellama-instruct", || Box::new(codellama_prompt::CodellamaPromptBuilder {}));
+    registry
+  }
+}
+
 pub mod llama2_prompt {
     // based on -> https://huggingface.co/TheBloke/Llama-2-7B-Chat-GGML/discussions/3
 
@@ -137,25 +227,259 @@ pub mod llama2_prompt {
       for i in 0..messages.len() {
         let message = &messages[i];
         match (i, message.role.as_str()) {
-          (0, "assistant") => bail!("First message cannot be from assistant"),
+          (0, "assistant") => bail!(ApiError::Unprocessable("First message cannot be from assistant".to_string())),
           (0, "system") => prompt.push_str(&format_sys(&message.content)),
           (_, "user") => prompt.push_str(&format_usr(&message.content)),
           (_, "assistant") => prompt.push_str(&format_asst(&message.content)),
-          (_, "system") => bail!("System messages can only be the first message"),
-          (_, _) => bail!("Invalid role"),
+          (_, "system") => bail!(ApiError::Unprocessable("System messages can only be the first message".to_string())),
+          (_, role) => bail!(ApiError::Unprocessable(format!("Invalid role '{}'", role))),
+        }
+      }
+
+      Ok(prompt)
+    }
+  }
+}
+
+pub mod codellama_prompt {
+    // based on -> https://github.com/facebookresearch/codellama/blob/main/llama/generation.py
+
+    /* example chat prompt:
+      <s>[INST] <<SYS>>
+      You are an expert python programmer.
+      <</SYS>>
+
+      <prompt> [/INST] <answer> </s><s>[INST] <prompt-second> [/INST]
+    */
+
+  use super::*;
+
+  pub struct CodellamaPromptBuilder {}
+
+  const B_INST: &str = "[INST]";
+  const E_INST: &str = "[/INST]";
+
+  fn format_sys(content: &str) -> String {
+    format!("<<SYS>>\n{}\n<</SYS>>\n\n", content.trim())
+  }
+
+  impl PromptBuilder for CodellamaPromptBuilder {
+    fn build_prompt(&self, messages: Vec<GenerationMessage>) -> Result<String> {
+      // the system prompt is folded into the first user instruction
+      let (system, dialog) = match messages.split_first() {
+        Some((first, rest)) if first.role == "system" => (Some(first.content.as_str()), rest),
+        _ => (None, messages.as_slice()),
+      };
+
+      let mut prompt = String::new();
+      for (i, message) in dialog.iter().enumerate() {
+        let expected = if i % 2 == 0 { "user" } else { "assistant" };
+        match message.role.as_str() {
+          "system" => bail!(ApiError::Unprocessable("System messages can only be the first message".to_string())),
+          "user" | "assistant" if message.role != expected =>
+            bail!(ApiError::Unprocessable("Messages must alternate between user and assistant starting with user".to_string())),
+          "user" => {
+            let content = match (i, system) {
+              (0, Some(sys)) => format!("{}{}", format_sys(sys), message.content.trim()),
+              _ => message.content.trim().to_string(),
+            };
+            prompt.push_str(&format!("<s>{} {} {}", B_INST, content, E_INST));
+          },
+          "assistant" => prompt.push_str(&format!(" {} </s>", message.content.trim())),
+          role => bail!(ApiError::Unprocessable(format!("Invalid role '{}'", role))),
         }
       }
 
+      if dialog.len() % 2 == 0 {
+        bail!(ApiError::Unprocessable("Last message must be from user".to_string()));
+      }
+
+      Ok(prompt)
+    }
+  }
+}
+
+pub mod template_prompt {
+    // renders Hugging Face style `chat_template` strings -> https://huggingface.co/docs/transformers/main/chat_templating
+
+    /* example ChatML template:
+      {% for message in messages %}{{ '<|im_start|>' + message.role + '\n' + message.content + '<|im_end|>' + '\n' }}{% endfor %}{% if add_generation_prompt %}{{ '<|im_start|>assistant\n' }}{% endif %}
+    */
+
+  use minijinja::{context, Environment, Error, ErrorKind};
+
+  use super::*;
+
+  pub struct TemplatePromptBuilder {
+    template: String,
+    bos_token: String,
+    eos_token: String,
+  }
+
+  impl TemplatePromptBuilder {
+    pub fn new(template: &str) -> Self {
+      Self {
+        template: template.to_string(),
+        bos_token: "<s>".to_string(),
+        eos_token: "</s>".to_string(),
+      }
+    }
+
+    pub fn with_special_tokens(self, bos_token: &str, eos_token: &str) -> Self {
+      Self { bos_token: bos_token.to_string(), eos_token: eos_token.to_string(), ..self }
+    }
+  }
+
+  /// Checks that a chat template parses, returning the syntax error if it doesn't.
+  pub fn check_template(template: &str) -> Result<(), String> {
+    Environment::new().template_from_str(template).map(|_| ()).map_err(|e| e.to_string())
+  }
+
+  fn raise_exception(message: String) -> Result<String, Error> {
+    Err(Error::new(ErrorKind::InvalidOperation, message))
+  }
+
+  impl PromptBuilder for TemplatePromptBuilder {
+    fn build_prompt(&self, messages: Vec<GenerationMessage>) -> Result<String> {
+      let mut env = Environment::new();
+      // templates from the hub lean on python string methods like .strip()
+      env.set_unknown_method_callback(minijinja_contrib::pycompat::unknown_method_callback);
+      env.add_function("raise_exception", raise_exception);
+
+      let prompt = env.render_str(&self.template, context! {
+        messages => messages,
+        bos_token => self.bos_token,
+        eos_token => self.eos_token,
+        add_generation_prompt => true,
+      }).map_err(|e| ApiError::Unprocessable(format!("Failed to render chat template: {}", e)))?;
+
       Ok(prompt)
     }
   }
 }
 
+/// Context window used for models without a known size.
+const DEFAULT_CONTEXT_WINDOW: u32 = 4096;
+
+/// Number of tokens the model can attend to, covering both the prompt and the generated text.
+pub fn context_window(model: &str) -> u32 {
+  match model {
+    "llama2-chat" => 4096,
+    "codellama-instruct" => 16384,
+    _ => DEFAULT_CONTEXT_WINDOW,
+  }
+}
+
+/// Rough token count for llama style tokenizers, which average about four characters per token.
+pub fn estimate_tokens(text: &str) -> u32 {
+  (text.chars().count() as u32).div_ceil(4)
+}
+
+/// A formatted prompt along with the indexes of any messages dropped to make it fit.
+#[derive(Debug)]
+pub struct TruncatedPrompt {
+  pub prompt: String,
+  pub dropped_messages: Vec<usize>,
+}
+
+/// What would be sent to the model for a request, returned by the preview endpoints in place of
+/// running inference.
+#[derive(Debug, Serialize)]
+pub struct PromptPreview {
+  pub model: String,
+  pub prompt: String,
+  /// The effective params, with the defaults filled in for anything not set on the request.
+  pub params: GenerationModelParams,
+  pub estimated_prompt_tokens: u32,
+  pub context_window: u32,
+  #[serde(skip_serializing_if = "Vec::is_empty")]
+  pub dropped_messages: Vec<usize>,
+}
+
+impl From<InferencingParams> for GenerationModelParams {
+  fn from(params: InferencingParams) -> Self {
+    Self {
+      max_tokens: Some(params.max_tokens),
+      repeat_penalty: Some(params.repeat_penalty),
+      repeat_penalty_last_n_token_count: Some(params.repeat_penalty_last_n_token_count),
+      temperature: Some(params.temperature),
+      top_k: Some(params.top_k),
+      top_p: Some(params.top_p),
+    }
+  }
+}
+
+/// Models accepted when the `allowed_models` variable is not set.
+const DEFAULT_ALLOWED_MODELS: &[&str] = &["llama2-chat", "codellama-instruct"];
+
+/// Embedding models accepted when the `allowed_embedding_models` variable is not set.
+const DEFAULT_EMBEDDING_MODELS: &[&str] = &["all-minilm-l6-v2"];
+
+/// The first of the allowed embedding models, used when the caller doesn't name one.
+pub fn default_embedding_model() -> String {
+  allowed_embedding_models().into_iter().next()
+    .unwrap_or_else(|| DEFAULT_EMBEDDING_MODELS[0].to_string())
+}
+
+/// Reads the comma separated `allowed_models` Spin variable, this should mirror the
+/// `ai_models` granted to the component in spin.toml.
+pub fn allowed_models() -> Vec<String> {
+  model_list("allowed_models", DEFAULT_ALLOWED_MODELS)
+}
+
+/// Same as `allowed_models` but for the `allowed_embedding_models` variable.
+pub fn allowed_embedding_models() -> Vec<String> {
+  model_list("allowed_embedding_models", DEFAULT_EMBEDDING_MODELS)
+}
+
+fn model_list(variable: &str, defaults: &[&str]) -> Vec<String> {
+  match spin_sdk::config::get(variable) {
+    Ok(models) => models
+      .split(',')
+      .map(|m| m.trim().to_string())
+      .filter(|m| !m.is_empty())
+      .collect(),
+    Err(_) => defaults.iter().map(|m| m.to_string()).collect(),
+  }
+}
+
+/// Resolves an embedding model by name, checking it against `allowed_embedding_models`.
+pub fn embedding_model(model: &str) -> Result<EmbeddingModel<'_>> {
+  let available_models = allowed_embedding_models();
+  if !available_models.iter().any(|m| m == model) {
+    return Err(ApiError::from(ModelNotAllowed { model: model.to_string(), available_models }).into());
+  }
+
+  match model {
+    "all-minilm-l6-v2" => Ok(EmbeddingModel::AllMiniLmL6V2),
+    other => Ok(EmbeddingModel::Other(other)),
+  }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ModelNotAllowed {
+  pub model: String,
+  pub available_models: Vec<String>,
+}
+
+impl fmt::Display for ModelNotAllowed {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "Model '{}' is not available, expected one of: {}", self.model, self.available_models.join(", "))
+  }
+}
+
+impl std::error::Error for ModelNotAllowed {}
+
 pub struct InferSdkBuilder {
   model: Option<String>,
   messages: Option<Vec<GenerationMessage>>,
   params: Option<GenerationModelParams>,
   prompt_builder: Option<Box<dyn PromptBuilder>>,
+  raw_prompt: Option<String>,
+  chat_template: Option<String>,
+  prompt_builders: PromptBuilderRegistry,
+  allowed_models: Option<Vec<String>>,
+  context_window: Option<u32>,
 }
 impl InferSdkBuilder {
   pub fn new() -> Self {
@@ -163,7 +487,12 @@ impl InferSdkBuilder {
       model: None,
       messages: None,
       params: None,
-      prompt_builder: None
+      prompt_builder: None,
+      raw_prompt: None,
+      chat_template: None,
+      prompt_builders: PromptBuilderRegistry::default(),
+      allowed_models: None,
+      context_window: None,
     }
   }
 
@@ -179,13 +508,39 @@ impl InferSdkBuilder {
     Self { params, ..self }
   }
 
+  /// Overrides the prompt builder that would otherwise be selected from the model.
   pub fn with_prompt_builder(self, prompt_builder: Box<dyn PromptBuilder>) -> Self {
     Self { prompt_builder: Some(prompt_builder), ..self }
   }
 
-  pub fn build_params(self) -> InferencingParams {
+  /// Uses the prompt as is, skipping prompt builders and messages entirely.
+  pub fn with_raw_prompt(self, raw_prompt: Option<String>) -> Self {
+    Self { raw_prompt, ..self }
+  }
+
+  /// Renders the prompt from a chat template instead of the model's registered builder.
+  pub fn with_chat_template(self, chat_template: Option<String>) -> Self {
+    let chat_template = chat_template.filter(|t| !t.trim().is_empty());
+    Self { chat_template, ..self }
+  }
+
+  pub fn with_prompt_builders(self, prompt_builders: PromptBuilderRegistry) -> Self {
+    Self { prompt_builders, ..self }
+  }
+
+  /// Restricts the accepted models, defaults to the `allowed_models` Spin variable.
+  pub fn with_allowed_models(self, allowed_models: Vec<String>) -> Self {
+    Self { allowed_models: Some(allowed_models), ..self }
+  }
+
+  /// Overrides the context window that would otherwise be looked up from the model.
+  pub fn with_context_window(self, context_window: u32) -> Self {
+    Self { context_window: Some(context_window), ..self }
+  }
+
+  pub fn build_params(&self) -> InferencingParams {
     let defaults = InferencingParams::default();
-    match self.params {
+    match &self.params {
       Some(p) => {
         InferencingParams {
           max_tokens: p.max_tokens.unwrap_or(defaults.max_tokens),
@@ -200,25 +555,237 @@ impl InferSdkBuilder {
     }
   }
 
-  pub fn build_model(&self) -> Result<InferencingModel<'static>> {
-    match self.model.as_deref() {
-      Some("llama2-chat") => Ok(InferencingModel::Llama2Chat),
-      Some("codellama-instruct") => Ok(InferencingModel::CodellamaInstruct),
-      // I have fought with this for hours and I don't understand how to return the inferencing model enum since its a temporary value
-      // other => Ok(InferencingModel::Other(other)),
-      _ => Err(anyhow!("Support for other models is not yet implemented")),
+  /// One set of params per combination in the sweep, with `build_params` as the base for the
+  /// params that aren't swept.
+  pub fn build_param_grid(&self, sweep: &sweep::ParamSweep) -> Result<Vec<InferencingParams>, ApiError> {
+    let model = self.model.as_deref().unwrap_or_default();
+    sweep.grid(self.build_params(), model, |f| format!("params.{}", f))
+  }
+
+  pub fn build_model(&self) -> Result<InferencingModel<'_>> {
+    let model = self.model.as_deref()
+      .ok_or_else(|| ApiError::BadRequest("Model must be provided".to_string()))?;
+
+    let available_models = match &self.allowed_models {
+      Some(models) => models.clone(),
+      None => allowed_models(),
+    };
+    if !available_models.iter().any(|m| m == model) {
+      return Err(ApiError::from(ModelNotAllowed { model: model.to_string(), available_models }).into());
+    }
+
+    match model {
+      "llama2-chat" => Ok(InferencingModel::Llama2Chat),
+      "codellama-instruct" => Ok(InferencingModel::CodellamaInstruct),
+      other => Ok(InferencingModel::Other(other)),
     }
   }
 
   pub fn build_prompt(&self) -> Result<String> {
-    match self.prompt_builder.as_deref() {
-      Some(pb) => {
-        match self.messages.as_deref() {
-          Some(messages) => pb.build_prompt(messages.to_vec()),
-          None => Err(anyhow!("Messages must be provided when using a prompt builder")),
-        }
+    self.build_truncated_prompt().map(|p| p.prompt)
+  }
+
+  /// Resolves the model, prompt and params exactly as they would be passed to inferencing.
+  pub fn build_preview(&self) -> Result<PromptPreview> {
+    let model = self.build_model()?;
+    let model = match model {
+      InferencingModel::Llama2Chat => "llama2-chat",
+      InferencingModel::CodellamaInstruct => "codellama-instruct",
+      InferencingModel::Other(other) => other,
+    };
+    let truncated = self.build_truncated_prompt()?;
+
+    Ok(PromptPreview {
+      model: model.to_string(),
+      estimated_prompt_tokens: estimate_tokens(&truncated.prompt),
+      context_window: self.context_window.unwrap_or_else(|| context_window(model)),
+      prompt: truncated.prompt,
+      params: self.build_params().into(),
+      dropped_messages: truncated.dropped_messages,
+    })
+  }
+
+  /// Builds the prompt, dropping the oldest turns until the prompt and `max_tokens` fit in the
+  /// context window. The leading system message and the latest message are always kept.
+  pub fn build_truncated_prompt(&self) -> Result<TruncatedPrompt> {
+    let window = self.context_window
+      .unwrap_or_else(|| context_window(self.model.as_deref().unwrap_or_default()));
+    let max_tokens = self.build_params().max_tokens;
+
+    // a raw prompt is the caller's responsibility, there are no turns to drop
+    if let Some(prompt) = &self.raw_prompt {
+      let prompt_tokens = estimate_tokens(prompt);
+      if prompt_tokens.saturating_add(max_tokens) > window {
+        bail!(ApiError::Unprocessable(format!(
+          "Prompt of ~{} tokens plus max_tokens of {} does not fit the {} token context window",
+          prompt_tokens, max_tokens, window)));
+      }
+      return Ok(TruncatedPrompt { prompt: prompt.clone(), dropped_messages: Vec::new() });
+    }
+
+    let selected: Box<dyn PromptBuilder>;
+    let pb = match (self.prompt_builder.as_deref(), self.chat_template.as_deref(), self.model.as_deref()) {
+      (Some(pb), _, _) => pb,
+      (None, Some(template), _) => {
+        selected = Box::new(template_prompt::TemplatePromptBuilder::new(template));
+        selected.as_ref()
       },
-      None => Err(anyhow!("Prompt builder must be provided")),
+      (None, None, Some(model)) => {
+        selected = self.prompt_builders.get(model)
+          .ok_or_else(|| ApiError::BadRequest(format!("No prompt builder is registered for model {}", model)))?;
+        selected.as_ref()
+      },
+      (None, None, None) => bail!(ApiError::BadRequest("Prompt builder or model must be provided".to_string())),
+    };
+
+    let messages = self.messages.as_deref()
+      .ok_or_else(|| ApiError::BadRequest("Messages must be provided when using a prompt builder".to_string()))?;
+
+    let first_droppable = match messages.first() {
+      Some(m) if m.role == "system" => 1,
+      _ => 0,
+    };
+
+    let mut kept: Vec<usize> = (0..messages.len()).collect();
+    loop {
+      let prompt = pb.build_prompt(kept.iter().map(|&i| messages[i].clone()).collect())?;
+      let prompt_tokens = estimate_tokens(&prompt);
+      if prompt_tokens.saturating_add(max_tokens) <= window {
+        let dropped_messages = (0..messages.len()).filter(|i| !kept.contains(i)).collect();
+        return Ok(TruncatedPrompt { prompt, dropped_messages });
+      }
+
+      let droppable = kept.len().saturating_sub(first_droppable + 1);
+      if droppable == 0 {
+        bail!(ApiError::Unprocessable(format!(
+          "Prompt of ~{} tokens plus max_tokens of {} does not fit the {} token context window",
+          prompt_tokens, max_tokens, window)));
+      }
+      // drop a whole user/assistant exchange when possible so the turns keep alternating
+      kept.drain(first_droppable..first_droppable + droppable.min(2));
+      // prompt templates expect the first turn after the system prompt to be the user's
+      while kept.len() > first_droppable + 1 && messages[kept[first_droppable]].role != "user" {
+        kept.remove(first_droppable);
+      }
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// Concatenates the message contents so every message costs exactly its own tokens.
+  struct ConcatPromptBuilder;
+
+  impl PromptBuilder for ConcatPromptBuilder {
+    fn build_prompt(&self, messages: Vec<GenerationMessage>) -> Result<String> {
+      Ok(messages.into_iter().map(|m| m.content).collect())
     }
   }
+
+  /// A message of ten estimated tokens, tagged so it can be found in the prompt.
+  fn message(role: &str, tag: char) -> GenerationMessage {
+    GenerationMessage { role: role.to_string(), content: tag.to_string().repeat(40) }
+  }
+
+  fn truncate(messages: Vec<GenerationMessage>, window: u32, max_tokens: u32) -> Result<TruncatedPrompt> {
+    InferSdkBuilder::new()
+      .with_prompt_builder(Box::new(ConcatPromptBuilder))
+      .with_messages(messages)
+      .with_params(Some(GenerationModelParams { max_tokens: Some(max_tokens), ..Default::default() }))
+      .with_context_window(window)
+      .build_truncated_prompt()
+  }
+
+  fn is_unprocessable(result: Result<TruncatedPrompt>) -> bool {
+    matches!(result.unwrap_err().downcast_ref::<ApiError>(), Some(ApiError::Unprocessable(_)))
+  }
+
+  #[test]
+  fn nothing_is_dropped_when_the_prompt_fits() {
+    let messages = vec![message("system", 's'), message("user", 'a'), message("assistant", 'b')];
+    let truncated = truncate(messages, 40, 10).unwrap();
+
+    assert!(truncated.dropped_messages.is_empty());
+    assert_eq!(estimate_tokens(&truncated.prompt), 30);
+  }
+
+  #[test]
+  fn oldest_exchanges_are_dropped_two_at_a_time() {
+    let messages = vec![
+      message("system", 's'), message("user", 'a'), message("assistant", 'b'),
+      message("user", 'c'), message("assistant", 'd'), message("user", 'e'),
+    ];
+    let truncated = truncate(messages, 40, 10).unwrap();
+
+    assert_eq!(truncated.dropped_messages, vec![1, 2, 3, 4]);
+    assert_eq!(truncated.prompt, format!("{}{}", "s".repeat(40), "e".repeat(40)));
+  }
+
+  #[test]
+  fn first_message_is_droppable_without_a_system_prompt() {
+    let messages = vec![message("user", 'a'), message("assistant", 'b'), message("user", 'c')];
+    let truncated = truncate(messages, 20, 10).unwrap();
+
+    assert_eq!(truncated.dropped_messages, vec![0, 1]);
+    assert_eq!(truncated.prompt, "c".repeat(40));
+  }
+
+  #[test]
+  fn a_single_message_is_dropped_when_only_one_is_droppable() {
+    let messages = vec![message("system", 's'), message("assistant", 'a'), message("user", 'b')];
+    let truncated = truncate(messages, 30, 10).unwrap();
+
+    assert_eq!(truncated.dropped_messages, vec![1]);
+    assert_eq!(truncated.prompt, format!("{}{}", "s".repeat(40), "b".repeat(40)));
+  }
+
+  #[test]
+  fn truncated_history_starts_with_a_user_message() {
+    let messages = vec![
+      message("system", 's'), message("assistant", 'a'), message("user", 'b'),
+      message("assistant", 'c'), message("user", 'd'),
+    ];
+    let truncated = truncate(messages, 40, 10).unwrap();
+
+    assert_eq!(truncated.dropped_messages, vec![1, 2, 3]);
+    assert_eq!(truncated.prompt, format!("{}{}", "s".repeat(40), "d".repeat(40)));
+
+    let messages = vec![
+      message("assistant", 'a'), message("user", 'b'), message("assistant", 'c'), message("user", 'd'),
+    ];
+    let truncated = truncate(messages, 30, 10).unwrap();
+
+    assert_eq!(truncated.dropped_messages, vec![0, 1, 2]);
+    assert_eq!(truncated.prompt, "d".repeat(40));
+  }
+
+  #[test]
+  fn template_syntax_errors_are_reported() {
+    assert!(template_prompt::check_template("{% for m in messages %}{{ m.content }}{% endfor %}").is_ok());
+    assert!(template_prompt::check_template("{% for m in messages %}{{ m.content }}").is_err());
+  }
+
+  #[test]
+  fn system_and_last_message_are_never_dropped() {
+    let messages = vec![
+      message("system", 's'), message("user", 'a'), message("assistant", 'b'), message("user", 'c'),
+    ];
+
+    assert!(is_unprocessable(truncate(messages, 25, 10)));
+  }
+
+  #[test]
+  fn huge_max_tokens_does_not_overflow() {
+    let messages = vec![message("user", 'a')];
+    assert!(is_unprocessable(truncate(messages, 4096, u32::MAX)));
+
+    let raw = InferSdkBuilder::new()
+      .with_raw_prompt(Some("a".repeat(40)))
+      .with_params(Some(GenerationModelParams { max_tokens: Some(u32::MAX), ..Default::default() }))
+      .with_context_window(4096)
+      .build_truncated_prompt();
+    assert!(is_unprocessable(raw));
+  }
 }
@@ -0,0 +1,233 @@
+use serde::Deserialize;
+use spin_sdk::llm::InferencingParams;
+
+use crate::{validation::{validate_params, Validator}, ApiError, GenerationModelParams};
+
+/// Most values a single param can take in one sweep.
+const MAX_SWEEP_VALUES: usize = 16;
+/// Most combinations a sweep can expand to.
+const MAX_COMBINATIONS: usize = 64;
+
+/// A fixed value, a list of values or an inclusive range walked in `step` increments.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(untagged)]
+pub enum SweepValues<T> {
+  List(Vec<T>),
+  Range { from: T, to: T, step: T },
+  Value(T),
+}
+
+pub trait SweepValue: Copy {
+  fn range(from: Self, to: Self, step: Self) -> Result<Vec<Self>, String>;
+}
+
+impl SweepValue for f32 {
+  fn range(from: f32, to: f32, step: f32) -> Result<Vec<f32>, String> {
+    if !(from.is_finite() && to.is_finite() && step.is_finite()) || step <= 0.0 || to < from {
+      return Err("range must have from <= to and a step greater than 0".to_string());
+    }
+    // the small tolerance keeps `to` in the range when the steps don't add up exactly
+    let steps = (to - from) / step + 1e-4;
+    // checked before the cast, huge quotients saturate and the count would overflow
+    if steps >= MAX_SWEEP_VALUES as f32 {
+      return Err(format!("range must have at most {} values", MAX_SWEEP_VALUES));
+    }
+    let count = steps.floor() as usize + 1;
+    // round so 0.1 + 2 * 0.1 is reported as 0.3
+    Ok((0..count).map(|i| ((from + i as f32 * step) * 10_000.0).round() / 10_000.0).collect())
+  }
+}
+
+impl SweepValue for u32 {
+  fn range(from: u32, to: u32, step: u32) -> Result<Vec<u32>, String> {
+    if step == 0 || to < from {
+      return Err("range must have from <= to and a step greater than 0".to_string());
+    }
+    let count = ((to - from) / step) as usize + 1;
+    if count > MAX_SWEEP_VALUES {
+      return Err(format!("range must have at most {} values", MAX_SWEEP_VALUES));
+    }
+    Ok((0..count as u32).map(|i| from + i * step).collect())
+  }
+}
+
+impl<T: SweepValue> SweepValues<T> {
+  pub fn values(&self) -> Result<Vec<T>, String> {
+    match self {
+      SweepValues::List(values) if values.is_empty() => Err("must not be empty".to_string()),
+      SweepValues::List(values) if values.len() > MAX_SWEEP_VALUES =>
+        Err(format!("must have at most {} values", MAX_SWEEP_VALUES)),
+      SweepValues::List(values) => Ok(values.clone()),
+      SweepValues::Range { from, to, step } => T::range(*from, *to, *step),
+      SweepValues::Value(value) => Ok(vec![*value]),
+    }
+  }
+}
+
+/// Values to try for each param, params that aren't swept keep their base value.
+#[derive(Debug, Default, Deserialize)]
+pub struct ParamSweep {
+  pub max_tokens: Option<SweepValues<u32>>,
+  pub repeat_penalty: Option<SweepValues<f32>>,
+  pub temperature: Option<SweepValues<f32>>,
+  pub top_k: Option<SweepValues<u32>>,
+  pub top_p: Option<SweepValues<f32>>,
+}
+
+/// Each swept value as params with only that field set.
+fn dimension<T: SweepValue>(
+  sweep: &Option<SweepValues<T>>,
+  param: impl Fn(T) -> GenerationModelParams,
+) -> Option<Result<Vec<GenerationModelParams>, String>> {
+  sweep.as_ref().map(|s| s.values().map(|values| values.into_iter().map(&param).collect()))
+}
+
+fn with_overrides(mut params: InferencingParams, overrides: &GenerationModelParams) -> InferencingParams {
+  if let Some(max_tokens) = overrides.max_tokens { params.max_tokens = max_tokens; }
+  if let Some(repeat_penalty) = overrides.repeat_penalty { params.repeat_penalty = repeat_penalty; }
+  if let Some(temperature) = overrides.temperature { params.temperature = temperature; }
+  if let Some(top_k) = overrides.top_k { params.top_k = top_k; }
+  if let Some(top_p) = overrides.top_p { params.top_p = top_p; }
+  params
+}
+
+impl ParamSweep {
+  /// The cartesian product of the swept values applied over `base`, in the order the params are
+  /// declared. Every value is range checked for `model`, `field` maps a param name onto the name
+  /// the caller used for it.
+  pub fn grid(
+    &self,
+    base: InferencingParams,
+    model: &str,
+    field: impl Fn(&str) -> String,
+  ) -> Result<Vec<InferencingParams>, ApiError> {
+    let dimensions = [
+      ("max_tokens", dimension(&self.max_tokens, |x| GenerationModelParams { max_tokens: Some(x), ..Default::default() })),
+      ("repeat_penalty", dimension(&self.repeat_penalty, |x| GenerationModelParams { repeat_penalty: Some(x), ..Default::default() })),
+      ("temperature", dimension(&self.temperature, |x| GenerationModelParams { temperature: Some(x), ..Default::default() })),
+      ("top_k", dimension(&self.top_k, |x| GenerationModelParams { top_k: Some(x), ..Default::default() })),
+      ("top_p", dimension(&self.top_p, |x| GenerationModelParams { top_p: Some(x), ..Default::default() })),
+    ];
+
+    let mut v = Validator::new();
+    let mut grid = vec![base];
+    for (name, values) in dimensions {
+      let values = match values {
+        None => continue,
+        Some(Ok(values)) => values,
+        Some(Err(message)) => {
+          v.check(&field(name), false, &message);
+          continue;
+        },
+      };
+      for value in &values {
+        validate_params(&mut v, model, value, &field);
+      }
+      if grid.len() * values.len() > MAX_COMBINATIONS {
+        v.check(&field(name), false,
+          &format!("has too many values, a sweep can expand to at most {} combinations", MAX_COMBINATIONS));
+        break;
+      }
+      grid = grid.iter()
+        .flat_map(|p| values.iter().map(move |value| with_overrides(*p, value)))
+        .collect();
+    }
+    v.finish()?;
+    Ok(grid)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn fields(result: Result<Vec<InferencingParams>, ApiError>) -> Vec<String> {
+    match result {
+      Err(ApiError::Validation(errors)) => errors.into_iter().map(|e| e.field).collect(),
+      other => panic!("expected a validation error, got {:?}", other.map(|grid| grid.len())),
+    }
+  }
+
+  fn grid(sweep: &ParamSweep) -> Result<Vec<InferencingParams>, ApiError> {
+    sweep.grid(InferencingParams::default(), "llama2-chat", |f| format!("params.{}", f))
+  }
+
+  #[test]
+  fn ranges_include_both_endpoints() {
+    assert_eq!(f32::range(0.1, 0.5, 0.1).unwrap(), vec![0.1, 0.2, 0.3, 0.4, 0.5]);
+    assert_eq!(u32::range(10, 40, 10).unwrap(), vec![10, 20, 30, 40]);
+    assert_eq!(f32::range(0.7, 0.7, 0.1).unwrap(), vec![0.7]);
+  }
+
+  #[test]
+  fn ranges_stop_before_to_when_the_step_does_not_divide() {
+    assert_eq!(f32::range(0.0, 1.0, 0.3).unwrap(), vec![0.0, 0.3, 0.6, 0.9]);
+    assert_eq!(u32::range(10, 100, 40).unwrap(), vec![10, 50, 90]);
+  }
+
+  #[test]
+  fn ranges_need_a_positive_step_and_from_at_most_to() {
+    assert!(f32::range(0.0, 1.0, 0.0).is_err());
+    assert!(f32::range(0.0, 1.0, -0.1).is_err());
+    assert!(f32::range(0.0, 1.0, f32::NAN).is_err());
+    assert!(f32::range(1.0, 0.0, 0.1).is_err());
+    assert!(u32::range(1, 10, 0).is_err());
+    assert!(u32::range(10, 1, 1).is_err());
+  }
+
+  #[test]
+  fn values_are_limited() {
+    assert_eq!(f32::range(0.0, 1.5, 0.1).unwrap().len(), MAX_SWEEP_VALUES);
+    assert!(f32::range(0.0, 1.6, 0.1).is_err());
+    assert!(SweepValues::List(Vec::<u32>::new()).values().is_err());
+    assert!(SweepValues::List((1..=17).collect::<Vec<u32>>()).values().is_err());
+  }
+
+  #[test]
+  fn huge_ranges_are_rejected_before_counting() {
+    assert!(f32::range(0.0, 1e30, 1e-30).is_err());
+    assert!(f32::range(-3e38, 3e38, 1.0).is_err());
+  }
+
+  #[test]
+  fn grid_is_the_product_over_the_base() {
+    let sweep = ParamSweep {
+      temperature: Some(SweepValues::List(vec![0.2, 0.8])),
+      top_k: Some(SweepValues::Range { from: 20, to: 60, step: 20 }),
+      ..Default::default()
+    };
+    let grid = grid(&sweep).unwrap();
+    let base = InferencingParams::default();
+
+    let points: Vec<(f32, u32)> = grid.iter().map(|p| (p.temperature, p.top_k)).collect();
+    assert_eq!(points, vec![(0.2, 20), (0.2, 40), (0.2, 60), (0.8, 20), (0.8, 40), (0.8, 60)]);
+    assert!(grid.iter().all(|p| p.max_tokens == base.max_tokens && p.top_p == base.top_p));
+  }
+
+  #[test]
+  fn grid_is_limited_to_max_combinations() {
+    let at_limit = ParamSweep {
+      temperature: Some(SweepValues::Range { from: 0.0, to: 1.5, step: 0.1 }),
+      top_k: Some(SweepValues::List(vec![10, 20, 30, 40])),
+      ..Default::default()
+    };
+    assert_eq!(grid(&at_limit).unwrap().len(), MAX_COMBINATIONS);
+
+    let over_limit = ParamSweep {
+      top_p: Some(SweepValues::List(vec![0.5, 0.9])),
+      ..at_limit
+    };
+    assert_eq!(fields(grid(&over_limit)), vec!["params.top_p"]);
+  }
+
+  #[test]
+  fn grid_reports_every_invalid_param() {
+    let sweep = ParamSweep {
+      max_tokens: Some(SweepValues::Value(0)),
+      temperature: Some(SweepValues::Range { from: 0.5, to: 0.1, step: 0.1 }),
+      top_p: Some(SweepValues::List(vec![0.5, 1.5])),
+      ..Default::default()
+    };
+    assert_eq!(fields(grid(&sweep)), vec!["params.max_tokens", "params.temperature", "params.top_p"]);
+  }
+}
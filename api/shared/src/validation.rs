@@ -0,0 +1,93 @@
+use serde::Serialize;
+
+use crate::{context_window, ApiError, GenerationModelParams, GenerationRequest};
+
+/// A single invalid field, `field` uses the dotted path of the request body e.g. `model.top_p`.
+#[derive(Debug, Clone, Serialize)]
+pub struct FieldError {
+  pub field: String,
+  pub message: String,
+}
+
+/// Collects field errors so every problem with a request is reported at once.
+#[derive(Debug, Default)]
+pub struct Validator {
+  errors: Vec<FieldError>,
+}
+
+impl Validator {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Records `message` against `field` when `valid` is false.
+  pub fn check(&mut self, field: &str, valid: bool, message: &str) -> &mut Self {
+    if !valid {
+      self.errors.push(FieldError { field: field.to_string(), message: message.to_string() });
+    }
+    self
+  }
+
+  pub fn errors(&self) -> &[FieldError] {
+    &self.errors
+  }
+
+  pub fn finish(self) -> Result<(), ApiError> {
+    match self.errors.is_empty() {
+      true => Ok(()),
+      false => Err(ApiError::Validation(self.errors)),
+    }
+  }
+}
+
+/// Range checks for inferencing params, `field` maps a `GenerationModelParams` field name onto
+/// the name the caller used for it.
+pub fn validate_params(
+  v: &mut Validator,
+  model: &str,
+  params: &GenerationModelParams,
+  field: impl Fn(&str) -> String,
+) {
+  let window = context_window(model);
+
+  if let Some(max_tokens) = params.max_tokens {
+    v.check(&field("max_tokens"), max_tokens > 0, "must be greater than 0");
+    v.check(&field("max_tokens"), max_tokens <= window,
+      &format!("must not exceed the {} token context window of {}", window, model));
+  }
+  if let Some(repeat_penalty) = params.repeat_penalty {
+    v.check(&field("repeat_penalty"), repeat_penalty.is_finite() && repeat_penalty > 0.0, "must be greater than 0");
+  }
+  if let Some(last_n) = params.repeat_penalty_last_n_token_count {
+    v.check(&field("repeat_penalty_last_n_token_count"), last_n <= window,
+      &format!("must not exceed the {} token context window of {}", window, model));
+  }
+  if let Some(temperature) = params.temperature {
+    v.check(&field("temperature"), temperature.is_finite() && temperature >= 0.0, "must be 0 or greater");
+  }
+  if let Some(top_k) = params.top_k {
+    v.check(&field("top_k"), top_k > 0, "must be greater than 0");
+  }
+  if let Some(top_p) = params.top_p {
+    v.check(&field("top_p"), top_p > 0.0 && top_p <= 1.0, "must be greater than 0 and at most 1");
+  }
+}
+
+/// Validates the params of a raw completion request, the model itself is checked against the
+/// allow-list when the model is built.
+pub fn validate_request(req: &GenerationRequest) -> Result<(), ApiError> {
+  let mut v = Validator::new();
+  match &req.prompt {
+    Some(prompt) => {
+      v.check("prompt", !prompt.is_empty(), "must not be empty");
+      v.check("messages", req.messages.is_empty(), "must be empty when a prompt is provided");
+    },
+    None => {
+      v.check("messages", !req.messages.is_empty(), "must contain at least one message");
+    },
+  }
+  if let Some(params) = &req.params {
+    validate_params(&mut v, req.model.as_deref().unwrap_or_default(), params, |f| format!("params.{}", f));
+  }
+  v.finish()
+}
@@ -1,6 +1,90 @@
-INSERT INTO apps (
+-- Example apps for local development, they are not part of the schema migrations so nothing
+-- is seeded in a real deployment. Apply once the apps api has created the schema, e.g.
+--
+--   sqlite3 .spin/sqlite_db.db < insert-test-data.sql
+--
+-- Apps that already exist are left alone.
+
+INSERT OR IGNORE INTO apps (
+    'name',
+    'description',
+    'system_prompt',
+    'model_name',
+    'model_max_tokens',
+    'model_temperature',
+    'model_repeat_penalty',
+    'model_repeat_penalty_last_n_tokens',
+    'model_top_k',
+    'model_top_p'
+) VALUES (
+    'basic-chat',
+    'A basic chat assistant with default values for all parameters.',
+    'You are a helpful, respectful and honest assistant. Always answer as helpfully as possible, while being safe. Your answers should not include any harmful, unethical, racist, sexist, toxic, dangerous, or illegal content. Please ensure that your responses are socially unbiased and positive in nature.
+
+If a question does not make any sense, or is not factually coherent, explain why instead of answering something not correct. If you don''t know the answer to a question, please don''t share false information.',
+    'llama2-chat',
+    100,
+    0.8,
+    1.1,
+    64,
+    40,
+    0.9
+);
+
+INSERT OR IGNORE INTO apps (
+    'name',
+    'description',
+    'system_prompt',
+    'model_name',
+    'model_max_tokens',
+    'model_temperature',
+    'model_repeat_penalty',
+    'model_repeat_penalty_last_n_tokens',
+    'model_top_k',
+    'model_top_p'
+) VALUES (
+    'basic-code',
+    'A basic code assistant with default values for all parameters.',
+    'You are a helpful, respectful and honest assistant. Always answer as helpfully as possible, while being safe. Your answers should not include any harmful, unethical, racist, sexist, toxic, dangerous, or illegal content. Please ensure that your responses are socially unbiased and positive in nature.
+
+If a question does not make any sense, or is not factually coherent, explain why instead of answering something not correct. If you don''t know the answer to a question, please don''t share false information.',
+    'codellama-instruct',
+    100,
+    0.8,
+    1.1,
+    64,
+    40,
+    0.9
+);
+
+INSERT OR IGNORE INTO apps (
+    'name',
+    'description',
+    'system_prompt',
+    'model_name',
+    'model_max_tokens',
+    'model_temperature',
+    'model_repeat_penalty',
+    'model_repeat_penalty_last_n_tokens',
+    'model_top_k',
+    'model_top_p'
+) VALUES (
+    'python-code-generator',
+    'A code generator example re-produced from github.com/ai-examples/code-generator-rs.',
+    'You are an expert python programmer. Your answer should start with a [CODE] tag and end with a [/CODE] tag. Write a full python script that does in accordance with the user''s prompt.',
+    'codellama-instruct',
+    400,
+    0.8,
+    1.1,
+    64,
+    40,
+    0.9
+);
+
+INSERT OR IGNORE INTO apps (
     'name',
     'description',
+    'system_prompt',
     'model_name',
     'model_max_tokens',
     'model_temperature',
@@ -9,15 +93,32 @@ INSERT INTO apps (
     'model_top_k',
     'model_top_p'
 ) VALUES (
-    'my-first-prompt-app',
-    'This is my first prompt app!',
-    'You are a helpful, respectful and honest assistant. Always answer as helpfully as possible, while being safe.  Your answers should not include any harmful, unethical, racist, sexist, toxic, dangerous, or illegal content. Please ensure that your responses are socially unbiased and positive in nature.\n\nIf a question does not make any sense, or is not factually coherent, explain why instead of answering something not correct. If you don''t know the answer to a question, please don''t share false information.'
+    'sentiment',
+    'A sentiment analysis example re-produced from github.com/ai-examples/sentiment-analysis-rs.',
+    'You are a bot that generates sentiment analysis responses. Respond with a single positive, negative, or neutral.
+
+Follow the pattern of the following examples:
+
+User: Hi, my name is Bob
+Bot: neutral
+
+User: I am so happy today
+Bot: positive
+
+User: I am so sad today
+Bot: negative',
     'llama2-chat',
-    75,
-    0.0,
+    6,
+    0.8,
     1.1,
     64,
     40,
     0.9
 );
 
+INSERT INTO eval_cases (app_name, name, messages, assertions)
+SELECT name, 'answers with a single sentiment label',
+    '[{"role":"user","content":"The delivery was late but the support team sorted it out quickly."}]',
+    '[{"type":"regex","pattern":"(?i)^(positive|negative|neutral)$"}]'
+FROM apps WHERE name = 'sentiment'
+AND NOT EXISTS (SELECT 1 FROM eval_cases WHERE app_name = 'sentiment');
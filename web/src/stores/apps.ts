@@ -1,6 +1,6 @@
 import { ref, computed } from 'vue';
 import { defineStore } from 'pinia';
-import api from '@/utils/api';
+import api, { ApiRequestError } from '@/utils/api';
 import { useCompletionsStore } from './completions';
 
 const defaultApp = {
@@ -23,6 +23,7 @@ const defaultApp = {
 export const useAppsStore = defineStore('apps', () => {
   const loading = ref(false);
   const error = ref<string | null>(null);
+  const fieldErrors = ref<Record<string, string>>({});
   const apps = ref<App[]>([]);
   const app = ref<App>(defaultApp);
 
@@ -39,10 +40,14 @@ export const useAppsStore = defineStore('apps', () => {
   async function callApi(fn: () => Promise<void>) {
     loading.value = true;
     error.value = null;
+    fieldErrors.value = {};
     try {
       await fn();
     } catch (err: any) {
       error.value = err.message;
+      if (err instanceof ApiRequestError) {
+        fieldErrors.value = Object.fromEntries(err.fieldErrors.map((e) => [e.field, e.message]));
+      }
     } finally {
       loading.value = false;
     }
@@ -78,6 +83,7 @@ export const useAppsStore = defineStore('apps', () => {
   return {
     loading,
     error,
+    fieldErrors,
     apps,
     app,
     appCompletions,
@@ -7,6 +7,7 @@ declare global {
     name: string;
     description: string;
     system_prompt: string;
+    chat_template?: string | null;
     model: {
       name: string;
       max_tokens: number;
@@ -18,6 +19,19 @@ declare global {
     }
   }
 
+  interface FieldError {
+    field: string;
+    message: string;
+  }
+
+  interface ApiErrorBody {
+    code: string;
+    message: string;
+    details?: {
+      errors?: FieldError[];
+    };
+  }
+
   interface CompletionMessage {
     role: string;
     content: string;
@@ -1,5 +1,24 @@
 const baseUrl = import.meta.env.VITE_API_HOST || '';
 
+export class ApiRequestError extends Error {
+  code: string;
+  fieldErrors: FieldError[];
+
+  constructor(body: ApiErrorBody) {
+    super(body.message);
+    this.code = body.code;
+    this.fieldErrors = body.details?.errors || [];
+  }
+}
+
+async function parseResponse<T>(response: Response): Promise<T> {
+  const body = await response.json();
+  if (!response.ok) {
+    throw new ApiRequestError(body);
+  }
+  return body;
+}
+
 async function fetchApps(): Promise<App[]> {
   const response = await fetch(`${baseUrl}/api/apps`);
   return await response.json();
@@ -18,7 +37,7 @@ async function createApp(app: App): Promise<App> {
     },
     body: JSON.stringify(app),
   });
-  return await response.json();
+  return await parseResponse<App>(response);
 }
 
 async function updateApp(app: App): Promise<App> {
@@ -29,7 +48,7 @@ async function updateApp(app: App): Promise<App> {
     },
     body: JSON.stringify(app),
   });
-  return await response.json();
+  return await parseResponse<App>(response);
 }
 
 async function deleteApp(name: string): Promise<void> {